@@ -5,185 +5,2889 @@ use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 const GIT_COMMAND_HISTORY_FILE_PATH: &str = ".git_command_history";
+const GIT_COMMAND_HISTORY_MIRROR_PATH: &str = ".git_command_history.jsonl";
+/// Names an executable to run after each command is recorded. It receives
+/// the new row on stdin as one JSON object, in the same shape as a line of
+/// the JSONL mirror: `{"id", "created_at", "command"}`, where `command` is
+/// the recorded GitCommandState. githist doesn't wait for it to finish.
+const POST_RECORD_HOOK_ENV: &str = "GITHIST_POST_RECORD";
+/// What nuke-file leaves in place of a scrubbed argument.
+const SCRUBBED_ARG: &str = "<scrubbed>";
+/// reset-to keeps HEAD as a branch named with this prefix and a timestamp.
+const BACKUP_BRANCH_PREFIX: &str = "githist-backup-";
+
+// Shown at the end of --help. 2 is what clap exits with for usage errors.
+const EXIT_CODES_HELP: &str = "\
+Exit codes:
+  0  success, including a git command that ran and failed
+  1  any other failure
+  2  invalid arguments
+  3  the history table is missing (run `command-history-init`)
+  4  git could not be started
+  5  the history database is locked by another process
+  6  the requested record (or branch) has no history";
 
 #[derive(Debug, Parser)]
-#[command(name = "git-history-wrapper", version = "0.1.0")]
+// no `help` subcommand, so `help` goes through to git like any other command
+#[command(
+    name = "git-history-wrapper",
+    version = "0.1.0",
+    disable_help_subcommand = true,
+    after_help = EXIT_CODES_HELP
+)]
 struct GitHistoryWrapper {
     #[clap(subcommand)]
     command: Option<Commands>,
+    /// Evict the oldest history rows after recording a command whenever the
+    /// database file grows beyond this many bytes.
+    #[clap(long, value_name = "BYTES")]
+    max_db_size: Option<u64>,
+    /// Print the git command that would run and how it would be recorded,
+    /// without running git or touching the history database. prune-history,
+    /// reset-to and backups print what they would change instead, and also take it
+    /// after the subcommand; the wrapper's other subcommands reject it.
+    #[clap(long, global = true)]
+    dry_run: bool,
+    /// Timezone timestamps are displayed in: `local`, `utc` or a fixed
+    /// offset such as `+05:00`. Timestamps are always stored in UTC.
+    #[clap(
+        long,
+        default_value = "local",
+        allow_hyphen_values = true,
+        value_parser = DisplayTimezone::parse
+    )]
+    tz: DisplayTimezone,
+    /// Record affected files by their resolved real path (following
+    /// symlinks) instead of the path as given.
+    #[clap(long)]
+    canonicalize: bool,
+    /// Also append every recorded command as a JSON line to
+    /// `.git_command_history.jsonl`.
+    #[clap(long)]
+    mirror_jsonl: bool,
+    /// Print the wrapper's own diagnostics (not git's output) to stderr at
+    /// this level and above: error, warn, info, debug or trace.
+    #[clap(long, value_name = "LEVEL")]
+    log_level: Option<log::LevelFilter>,
+    /// Kill git if it hasn't finished after this many seconds; the command
+    /// is still recorded, marked as timed out. No timeout by default.
+    #[clap(long, value_name = "SECS")]
+    timeout: Option<u64>,
+    /// Format of the diagnostics enabled by --log-level.
+    #[clap(long, value_enum, default_value = "text")]
+    log_format: LogFormat,
+    /// Run git as if started in DIR, like `git -C`. Repeated values are
+    /// joined, each relative to the last. The database stays where it is.
+    #[clap(short = 'C', value_name = "DIR")]
+    directory: Vec<std::path::PathBuf>,
+    /// Print how long each phase of recording a git command took to stderr.
+    #[clap(long)]
+    profile: bool,
+    /// Pass read-only git commands straight through without recording them.
+    #[clap(long)]
+    record_only_mutating: bool,
+    /// Store at most this many affected files per command; the rest are
+    /// counted but not listed.
+    #[clap(long, value_name = "N", default_value = "100")]
+    max_affected_files: usize,
+    /// Ask before running a destructive git command: a forced push,
+    /// `reset --hard`, `clean -f` (without -n) or `branch -D`.
+    #[clap(long)]
+    confirm_destructive: bool,
+    /// Answer yes to every confirmation prompt: --confirm-destructive's and
+    /// those of forget, restore-db and reset-to. It can also follow those
+    /// subcommands.
+    #[clap(long, global = true)]
+    yes: bool,
+    /// Show history from every repository in the database, not just the
+    /// current one.
+    #[clap(long)]
+    all_repos: bool,
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Default)]
+struct Profile {
+    phases: Vec<(&'static str, std::time::Duration)>,
+}
+
+impl Profile {
+    fn time<T>(&mut self, phase: &'static str, f: impl FnOnce() -> T) -> T {
+        let start = std::time::Instant::now();
+        let result = f();
+        self.phases.push((phase, start.elapsed()));
+        result
+    }
+
+    fn print(&self) {
+        let total: std::time::Duration = self.phases.iter().map(|(_, elapsed)| *elapsed).sum();
+        for (phase, elapsed) in self.phases.iter().chain([&("total", total)]) {
+            eprintln!("{:<14} {:>9.3}ms", phase, elapsed.as_secs_f64() * 1000.0);
+        }
+    }
+}
+
+struct StderrLogger {
+    format: LogFormat,
+}
+
+impl log::Log for StderrLogger {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        // filtering happens through log::set_max_level
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        match self.format {
+            LogFormat::Text => eprintln!("githist {}: {}", record.level(), record.args()),
+            LogFormat::Json => eprintln!(
+                "{}",
+                serde_json::json!({
+                    "timestamp": time::OffsetDateTime::now_utc().to_string(),
+                    "level": record.level().as_str(),
+                    "target": record.target(),
+                    "message": record.args().to_string(),
+                })
+            ),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+#[derive(Debug, Clone, Copy)]
+enum DisplayTimezone {
+    Local,
+    Utc,
+    Fixed(time::UtcOffset),
+}
+
+impl DisplayTimezone {
+    fn parse(tz: &str) -> Result<DisplayTimezone, String> {
+        match tz {
+            "local" => Ok(DisplayTimezone::Local),
+            "utc" | "UTC" => Ok(DisplayTimezone::Utc),
+            offset => parse_utc_offset(offset)
+                .map(DisplayTimezone::Fixed)
+                .ok_or_else(|| format!("expected local, utc or +HH:MM, got `{}`", offset)),
+        }
+    }
+
+    fn offset_at(self, datetime: time::OffsetDateTime) -> time::UtcOffset {
+        match self {
+            DisplayTimezone::Local => local_offset_at(datetime).unwrap_or(time::UtcOffset::UTC),
+            DisplayTimezone::Utc => time::UtcOffset::UTC,
+            DisplayTimezone::Fixed(offset) => offset,
+        }
+    }
+
+    /// Renders a stored `created_at` in this timezone, leaving anything that
+    /// doesn't parse as it was stored.
+    fn format_created_at(self, created_at: &str) -> String {
+        let Some(datetime) = parse_created_at(created_at) else {
+            return created_at.to_string();
+        };
+        let datetime = datetime.to_offset(self.offset_at(datetime));
+        let offset = datetime.offset();
+        format!(
+            "{}-{:02}-{:02} {:02}:{:02}:{:02} {}{:02}:{:02}",
+            datetime.year(),
+            datetime.month() as u8,
+            datetime.day(),
+            datetime.hour(),
+            datetime.minute(),
+            datetime.second(),
+            if offset.is_negative() { '-' } else { '+' },
+            offset.whole_hours().abs(),
+            offset.minutes_past_hour().abs()
+        )
+    }
 }
 
+fn format_duration(duration: time::Duration) -> String {
+    let minutes = duration.whole_minutes();
+    match (minutes / (24 * 60), minutes / 60 % 24, minutes % 60) {
+        (0, 0, minutes) => format!("{}m", minutes),
+        (0, hours, minutes) => format!("{}h {}m", hours, minutes),
+        (days, hours, minutes) => format!("{}d {}h {}m", days, hours, minutes),
+    }
+}
+
+// Parses `+HH:MM`, `-HH:MM` and `+HH:MM:SS`.
+fn parse_utc_offset(offset: &str) -> Option<time::UtcOffset> {
+    let sign: i8 = match offset.get(..1)? {
+        "+" => 1,
+        "-" => -1,
+        _ => return None,
+    };
+    let mut parts = offset[1..].split(':');
+    let hours: i8 = parts.next()?.parse().ok()?;
+    let minutes: i8 = parts.next()?.parse().ok()?;
+    let seconds: i8 = parts
+        .next()
+        .map_or(Some(0), |seconds| seconds.parse().ok())?;
+    if parts.next().is_some() {
+        return None;
+    }
+    time::UtcOffset::from_hms(sign * hours, sign * minutes, sign * seconds).ok()
+}
+
+// `created_at` is written with `OffsetDateTime`'s `Display` impl, e.g.
+// `2024-01-15 9:05:03.123456789 +00:00:00`.
+fn parse_created_at(created_at: &str) -> Option<time::OffsetDateTime> {
+    let mut parts = created_at.split(' ');
+    let (date, clock, offset) = (parts.next()?, parts.next()?, parts.next()?);
+    let mut date = date.splitn(3, '-');
+    let year: i32 = date.next()?.parse().ok()?;
+    let month: u8 = date.next()?.parse().ok()?;
+    let day: u8 = date.next()?.parse().ok()?;
+    let (clock, subsecond) = clock.split_once('.').unwrap_or((clock, "0"));
+    let mut clock = clock.splitn(3, ':');
+    let hour: u8 = clock.next()?.parse().ok()?;
+    let minute: u8 = clock.next()?.parse().ok()?;
+    let second: u8 = clock.next()?.parse().ok()?;
+    let nanosecond: u32 = format!("{:0<9}", subsecond).get(..9)?.parse().ok()?;
+    let date =
+        time::Date::from_calendar_date(year, time::Month::try_from(month).ok()?, day).ok()?;
+    let clock = time::Time::from_hms_nano(hour, minute, second, nanosecond).ok()?;
+    Some(time::PrimitiveDateTime::new(date, clock).assume_offset(parse_utc_offset(offset)?))
+}
+
+#[cfg(unix)]
+fn local_offset_at(datetime: time::OffsetDateTime) -> Option<time::UtcOffset> {
+    let timestamp = datetime.unix_timestamp() as libc::time_t;
+    // SAFETY: `tm` is plain old data that localtime_r fills in, and both
+    // pointers are valid for the duration of the call.
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&timestamp, &mut tm) }.is_null() {
+        return None;
+    }
+    time::UtcOffset::from_whole_seconds(tm.tm_gmtoff as i32).ok()
+}
+
+#[cfg(unix)]
+fn get_terminal_width() -> Option<u16> {
+    use std::io::IsTerminal;
+    use std::os::fd::AsRawFd;
+    let stdout = std::io::stdout();
+    if !stdout.is_terminal() {
+        return None;
+    }
+    // SAFETY: TIOCGWINSZ only writes a `winsize` through the pointer we pass.
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    if unsafe { libc::ioctl(stdout.as_raw_fd(), libc::TIOCGWINSZ, &mut size) } != 0 {
+        return None;
+    }
+    Some(size.ws_col).filter(|&cols| cols > 0)
+}
+
+#[cfg(not(unix))]
+fn get_terminal_width() -> Option<u16> {
+    None
+}
+
+#[cfg(not(unix))]
+fn local_offset_at(_datetime: time::OffsetDateTime) -> Option<time::UtcOffset> {
+    None
+}
+
+// The wrapper's own subcommands. Their names are reserved: clap matches
+// them before falling through to `Other`, so they're never forwarded to git
+// or recorded, even when githist is installed as `git`. New ones mustn't
+// share a name with a git command.
 #[derive(Debug, Subcommand)]
 enum Commands {
     CommandHistoryInit,
     MutateActions,
+    /// Record the current `git status --porcelain=v2` as a checkpoint row
+    Snapshot,
+    /// Delete a single record by id (or unique id prefix)
+    Forget {
+        id: String,
+    },
+    /// Delete all but the newest N records, across every repository
+    PruneHistory {
+        #[clap(long, value_name = "N")]
+        keep_last: usize,
+    },
+    /// Report when a branch was first and last worked on
+    BranchLifetime {
+        branch: String,
+    },
+    /// Recreate the history table from the `.git_command_history.jsonl`
+    /// mirror, replacing everything currently in it
+    RestoreDb,
+    /// Check the database structure, and optionally each row's checksum
+    Verify {
+        /// Also recompute row checksums to find rows edited outside githist
+        #[clap(long)]
+        checksums: bool,
+    },
+    /// Check that git and the history database are set up for recording
+    Doctor {
+        /// Apply safe repairs before checking: migrations, missing indexes,
+        /// WAL journaling and vacuuming a fragmented file
+        #[clap(long)]
+        fix: bool,
+    },
+    /// Reclaim unused space in the history database with VACUUM
+    Compact,
+    /// Run and record a JSON array of git argument lists read from stdin
+    BatchRun {
+        /// Keep going after a command fails instead of stopping there
+        #[clap(long)]
+        continue_on_error: bool,
+    },
+    /// Hard-reset to the commit a record was made at, backing up HEAD first
+    ResetTo {
+        id: String,
+    },
+    /// Group history into working sessions separated by idle gaps
+    Sessions {
+        /// Start a new session after this many minutes without a command
+        #[clap(long, value_name = "MINUTES", default_value = "30")]
+        gap: u32,
+    },
+    /// Print recorded commands as a bash script that runs them again
+    ExportScript {
+        /// Only include this kind of command, e.g. `commit` (repeatable)
+        #[clap(long = "command", value_name = "KIND")]
+        commands: Vec<String>,
+        /// Only include commands run on this branch
+        #[clap(long)]
+        branch: Option<String>,
+    },
+    /// List the backup branches made by reset-to
+    Backups {
+        /// Delete all but the newest N backup branches
+        #[clap(long, value_name = "N")]
+        keep: Option<usize>,
+    },
+    /// Scrub a path from every recorded command and from the JSONL mirror:
+    /// affected files, arguments, file changes and snapshot status. Arguments
+    /// are matched as typed, affected files as stored (repo-relative)
+    NukeFile {
+        path: String,
+        /// Delete rows that referenced the path instead of rewriting them
+        #[clap(long)]
+        delete_rows: bool,
+    },
     #[clap(external_subcommand)]
     Other(Vec<String>),
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = GitHistoryWrapper::parse();
-    match args.command {
-        Some(Commands::CommandHistoryInit) => {
-            let conn = rusqlite::Connection::open(GIT_COMMAND_HISTORY_FILE_PATH)?;
-            conn.execute(
-                "CREATE TABLE IF NOT EXISTS git_command_history (
-                id TEXT PRIMARY KEY,
-                command TEXT NOT NULL,
-                created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
-            )",
-                [],
-            )?;
-        }
-        Some(Commands::MutateActions) => {
-            let conn = rusqlite::Connection::open(GIT_COMMAND_HISTORY_FILE_PATH)?;
-            let mut stmt = conn.prepare("SELECT * FROM git_command_history")?;
-            let mut rows = stmt.query([])?;
-            while let Some(row) = rows.next()? {
-                let id: String = row.get(0)?;
-                let command: String = serde_json::from_str(&row.get(1)?)?;
-                if !command_is_mutate(GitCommandState::extract_git_command(&command)?) {
-                    continue;
-                }
-                let created_at: String = row.get(2)?;
-                println!("{} {} {}", id, command, created_at);
-            }
-        }
-        Some(Commands::Other(args)) => {
-            // here we've received a git command, we should forward it to git
-            // and then save it to the database
-            let command = args.join(" ");
-            let output = std::process::Command::new("git")
-                .args(args)
-                .output()
-                .expect("failed to execute process");
-            let output = String::from_utf8(output.stdout).unwrap();
-            println!("{}", output);
-            let conn = rusqlite::Connection::open(GIT_COMMAND_HISTORY_FILE_PATH)?;
-            add_command_history(&conn, &command)?;
-        }
-        None => {
-            println!("No subcommand was used");
-        }
+impl Commands {
+    // The rest either don't change anything or have no way to show what
+    // they would change, so --dry-run is refused rather than ignored.
+    fn supports_dry_run(&self) -> bool {
+        matches!(
+            self,
+            Commands::PruneHistory { .. }
+                | Commands::ResetTo { .. }
+                | Commands::Backups { .. }
+                | Commands::Other(_)
+        )
+    }
+}
+
+/// Errors the wrapper distinguishes so that each kind maps to its own process
+/// exit code, as listed in EXIT_CODES_HELP. Anything else that bubbles up to
+/// `main` exits with `1`.
+#[derive(Debug)]
+enum GithistError {
+    NotFound(String),
+    NoTable,
+    GitFailed(std::io::Error),
+    DbLocked,
+}
+
+impl GithistError {
+    fn exit_code(&self) -> u8 {
+        match self {
+            GithistError::NotFound(_) => 6,
+            GithistError::NoTable => 3,
+            GithistError::GitFailed(_) => 4,
+            GithistError::DbLocked => 5,
+        }
+    }
+
+    // rusqlite errors reach `main` untouched through `?`, so classify the
+    // ones we care about here rather than wrapping every call site.
+    fn from_sqlite(err: &rusqlite::Error) -> Option<GithistError> {
+        match err {
+            rusqlite::Error::SqliteFailure(err, _)
+                if matches!(
+                    err.code,
+                    rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked
+                ) =>
+            {
+                Some(GithistError::DbLocked)
+            }
+            rusqlite::Error::SqliteFailure(_, Some(message))
+                if message.starts_with("no such table") =>
+            {
+                Some(GithistError::NoTable)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for GithistError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GithistError::NotFound(what) => write!(f, "{} not found", what),
+            GithistError::NoTable => write!(
+                f,
+                "history table does not exist, run `command-history-init` first"
+            ),
+            GithistError::GitFailed(err) => write!(f, "failed to execute git: {}", err),
+            GithistError::DbLocked => write!(f, "history database is locked"),
+        }
+    }
+}
+
+impl std::error::Error for GithistError {}
+
+fn exit_code(err: &(dyn std::error::Error + 'static)) -> u8 {
+    if let Some(err) = err.downcast_ref::<GithistError>() {
+        return err.exit_code();
+    }
+    err.downcast_ref::<rusqlite::Error>()
+        .and_then(GithistError::from_sqlite)
+        .map_or(1, |err| err.exit_code())
+}
+
+fn main() -> std::process::ExitCode {
+    match run() {
+        Ok(()) => std::process::ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            std::process::ExitCode::from(exit_code(err.as_ref()))
+        }
+    }
+}
+
+fn run() -> Result<(), Box<dyn std::error::Error>> {
+    let mut args = GitHistoryWrapper::parse();
+    if let Some(level) = args.log_level {
+        let logger = StderrLogger {
+            format: args.log_format,
+        };
+        log::set_boxed_logger(Box::new(logger))?;
+        log::set_max_level(level);
+    }
+    if !args.directory.is_empty() {
+        let dir = args
+            .directory
+            .iter()
+            .fold(std::env::current_dir()?, |dir, next| dir.join(next));
+        GIT_DIRECTORY.get_or_init(|| normalize_path(&dir));
+    }
+    if args.dry_run && !args.command.as_ref().is_none_or(Commands::supports_dry_run) {
+        return Err(
+            "--dry-run is only supported by git commands, prune-history, reset-to and backups"
+                .into(),
+        );
+    }
+    match args.command.take() {
+        Some(Commands::CommandHistoryInit) => {
+            let mut conn = rusqlite::Connection::open(GIT_COMMAND_HISTORY_FILE_PATH)?;
+            init_history_table(&mut conn)?;
+        }
+        Some(Commands::MutateActions) => {
+            let conn = open_history_db()?;
+            let mut stmt = conn.prepare(&format!(
+                "SELECT * FROM git_command_history WHERE {}",
+                REPO_FILTER
+            ))?;
+            let mut rows = stmt.query([repo_filter(args.all_repos)])?;
+            while let Some(row) = rows.next()? {
+                let id: String = row.get(0)?;
+                let command: String = row.get(1)?;
+                let state: GitCommandState = serde_json::from_str(&command)?;
+                let lists_tags =
+                    matches!(&state.tag, Some(tag) if matches!(tag.action, TagAction::List));
+                if !command_is_mutate(state.command) || lists_tags {
+                    continue;
+                }
+                let created_at: String = row.get(2)?;
+                println!(
+                    "{} {} {}",
+                    id,
+                    command,
+                    args.tz.format_created_at(&created_at)
+                );
+            }
+        }
+        Some(Commands::Snapshot) => {
+            let conn = open_history_db()?;
+            let state = GitCommandState::snapshot().map_err(GithistError::GitFailed)?;
+            add_command_history(&conn, state, args.mirror_jsonl)?;
+        }
+        Some(Commands::Forget { id }) => {
+            let conn = open_history_db()?;
+            let (id, command, created_at) = find_record(&conn, &id)?;
+            println!(
+                "{} {} {}",
+                id,
+                command,
+                args.tz.format_created_at(&created_at)
+            );
+            if !args.yes && !confirm("Delete this record?")? {
+                println!("Aborted");
+                return Ok(());
+            }
+            conn.execute("DELETE FROM git_command_history WHERE id = ?1", [&id])?;
+            println!("Deleted {}", id);
+        }
+        Some(Commands::PruneHistory { keep_last }) => {
+            let conn = open_history_db()?;
+            // rowid follows insertion order, unlike created_at, which doesn't
+            // sort as text
+            let older = "FROM git_command_history WHERE rowid NOT IN (
+                SELECT rowid FROM git_command_history ORDER BY rowid DESC LIMIT ?1
+            )";
+            if args.dry_run {
+                let count: usize =
+                    conn.query_row(&format!("SELECT COUNT(*) {}", older), [keep_last], |row| {
+                        row.get(0)
+                    })?;
+                println!("would delete {} rows", count);
+            } else {
+                let deleted = conn.execute(&format!("DELETE {}", older), [keep_last])?;
+                println!("deleted {} rows", deleted);
+            }
+        }
+        Some(Commands::BranchLifetime { branch }) => {
+            let conn = open_history_db()?;
+            print_branch_lifetime(&conn, &branch, args.tz, repo_filter(args.all_repos))?;
+        }
+        Some(Commands::RestoreDb) => {
+            if !args.yes && !confirm("Replace the history database with the JSONL mirror?")? {
+                println!("Aborted");
+                return Ok(());
+            }
+            let mut conn = rusqlite::Connection::open(GIT_COMMAND_HISTORY_FILE_PATH)?;
+            let (restored, skipped) = restore_from_mirror(&mut conn)?;
+            println!(
+                "restored {} rows, skipped {} malformed lines",
+                restored, skipped
+            );
+        }
+        Some(Commands::Verify { checksums }) => {
+            let conn = open_history_db()?;
+            let integrity: String =
+                conn.query_row("PRAGMA integrity_check", [], |row| row.get(0))?;
+            println!("integrity_check: {}", integrity);
+            let mut failed = integrity != "ok";
+            if checksums {
+                let (mismatched, unchecked) = verify_checksums(&conn)?;
+                for id in &mismatched {
+                    println!("checksum mismatch: {}", id);
+                }
+                if unchecked > 0 {
+                    println!("{} rows predate checksums and were not checked", unchecked);
+                }
+                failed |= !mismatched.is_empty();
+            }
+            if failed {
+                return Err("verification failed".into());
+            }
+        }
+        Some(Commands::Compact) => {
+            // opening would create an empty database just to vacuum it
+            if !std::path::Path::new(GIT_COMMAND_HISTORY_FILE_PATH).exists() {
+                return Err(GithistError::NoTable.into());
+            }
+            eprintln!(
+                "githist: compacting needs exclusive access to the database and may take a while"
+            );
+            let conn = rusqlite::Connection::open(GIT_COMMAND_HISTORY_FILE_PATH)?;
+            let before = database_size()?;
+            conn.execute("VACUUM", [])?;
+            // fold the WAL back in too; a no-op outside WAL mode
+            conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+            let after = database_size()?;
+            println!("{} bytes before, {} bytes after", before, after);
+        }
+        Some(Commands::Doctor { fix }) => {
+            if fix {
+                for (status, message) in doctor_fix()? {
+                    println!("[{}] {}", status, message);
+                }
+            }
+            let checks = doctor();
+            for (status, message) in &checks {
+                println!("[{}] {}", status, message);
+            }
+            let failures = checks
+                .iter()
+                .filter(|(status, _)| matches!(status, CheckStatus::Fail))
+                .count();
+            if failures > 0 {
+                return Err(format!("doctor found {} failing checks", failures).into());
+            }
+        }
+        Some(Commands::NukeFile { path, delete_rows }) => {
+            let mut conn = open_history_db()?;
+            let (rewritten, deleted) = nuke_file(&mut conn, &path, delete_rows)?;
+            println!(
+                "scrubbed {} from {} rows, deleted {} rows",
+                path, rewritten, deleted
+            );
+            // otherwise restore-db would bring the path straight back
+            if let Some(lines) = nuke_file_in_mirror(&path, delete_rows)? {
+                println!(
+                    "scrubbed {} from {} lines of {}",
+                    path, lines, GIT_COMMAND_HISTORY_MIRROR_PATH
+                );
+            }
+        }
+        Some(Commands::ResetTo { id }) => {
+            let conn = open_history_db()?;
+            let (id, command, _) = find_record(&conn, &id)?;
+            let state: GitCommandState = serde_json::from_str(&command)?;
+            let commit = state.current_commit.trim_end().to_string();
+            if commit.is_empty() || commit == "HEAD" {
+                return Err(format!("record {} has no commit to reset to", id).into());
+            }
+            let backup = format!(
+                "{}{}",
+                BACKUP_BRANCH_PREFIX,
+                time::OffsetDateTime::now_utc().unix_timestamp()
+            );
+            println!("reset --hard to {} from record {}", commit, id);
+            println!("HEAD will be kept as branch {}", backup);
+            if args.dry_run {
+                println!("would run: git branch {}", backup);
+                println!("would run: git reset --hard {}", commit);
+                return Ok(());
+            }
+            if !args.yes && !confirm("Uncommitted changes will be lost. Reset?")? {
+                println!("Aborted");
+                return Ok(());
+            }
+            for git_args in [
+                vec!["branch".to_string(), backup],
+                vec!["reset".to_string(), "--hard".to_string(), commit],
+            ] {
+                let mut run = run_git_command(&args, &git_args)?;
+                print!("{}", String::from_utf8_lossy(&run.output.stdout));
+                record_git_command(&args, &git_args, &mut run)?;
+                if !run.output.status.success() {
+                    eprint!("{}", String::from_utf8_lossy(&run.output.stderr));
+                    return Err(format!("git {} failed", git_args.join(" ")).into());
+                }
+            }
+        }
+        Some(Commands::Sessions { gap }) => {
+            let conn = open_history_db()?;
+            print_sessions(
+                &conn,
+                time::Duration::minutes(gap.into()),
+                args.tz,
+                repo_filter(args.all_repos),
+            )?;
+        }
+        Some(Commands::ExportScript { commands, branch }) => {
+            let conn = open_history_db()?;
+            print_script(
+                &conn,
+                &commands,
+                branch.as_deref(),
+                args.tz,
+                repo_filter(args.all_repos),
+            )?;
+        }
+        Some(Commands::Backups { keep }) => {
+            let backups = list_backup_branches()?;
+            let prune = keep.map_or(0, |keep| backups.len().saturating_sub(keep));
+            for branch in &backups[..prune] {
+                if args.dry_run {
+                    println!("would delete {}", branch);
+                    continue;
+                }
+                let git_args = vec!["branch".to_string(), "-D".to_string(), branch.clone()];
+                let mut run = run_git_command(&args, &git_args)?;
+                record_git_command(&args, &git_args, &mut run)?;
+                if !run.output.status.success() {
+                    return Err(format!("could not delete {}", branch).into());
+                }
+                println!("deleted {}", branch);
+            }
+            for branch in &backups[prune..] {
+                println!("{}", branch);
+            }
+        }
+        Some(Commands::BatchRun { continue_on_error }) => {
+            let batch: Vec<Vec<String>> = serde_json::from_reader(std::io::stdin())?;
+            let mut results = vec![];
+            let mut failed = false;
+            for git_args in batch {
+                // stdin holds the batch, so there's no way to ask; earlier
+                // commands have already run, so report the refusal as a
+                // result rather than losing theirs
+                if let (true, false, Some(reason)) = (
+                    args.confirm_destructive,
+                    args.yes,
+                    destructive_reason(&git_args),
+                ) {
+                    results.push(serde_json::json!({
+                        "args": git_args,
+                        "exit_code": null,
+                        "error": format!("not run: {}, pass --yes to allow it", reason),
+                    }));
+                    failed = true;
+                    break;
+                }
+                let mut run = run_git_command(&args, &git_args)?;
+                // stdout carries the results, so git's output goes to stderr
+                eprint!("{}", String::from_utf8_lossy(&run.output.stdout));
+                let recorded = record_git_command(&args, &git_args, &mut run);
+                let exit_code = run.output.status.code().filter(|_| !run.timed_out);
+                if let Err(err) = recorded {
+                    // git has run, so it belongs in the results, but nothing
+                    // after it would be recorded either
+                    results.push(serde_json::json!({
+                        "args": git_args,
+                        "exit_code": exit_code,
+                        "error": format!("not recorded: {}", err),
+                    }));
+                    failed = true;
+                    break;
+                }
+                results.push(serde_json::json!({ "args": git_args, "exit_code": exit_code }));
+                if exit_code != Some(0) {
+                    failed = true;
+                    if !continue_on_error {
+                        break;
+                    }
+                }
+            }
+            println!("{}", serde_json::to_string(&results)?);
+            if failed {
+                return Err("a batch command failed".into());
+            }
+        }
+        Some(Commands::Other(git_args)) => {
+            // here we've received a git command, we should forward it to git
+            // and then save it to the database
+            let command = git_args.join(" ");
+            if args.dry_run {
+                let git_command = GitCommandState::extract_git_command(&command)
+                    .unwrap_or(GitCommand::InvalidCommand);
+                println!("would run: git {}", command);
+                println!(
+                    "would record: {} (mutating: {})",
+                    serde_json::to_string(&git_command)?,
+                    invocation_is_mutate(git_command, &git_args)
+                );
+                println!(
+                    "files affected: {:?}",
+                    GitCommandState::process_affected_files(&command, args.canonicalize)?
+                );
+                return Ok(());
+            }
+            if let (true, false, Some(reason)) = (
+                args.confirm_destructive,
+                args.yes,
+                destructive_reason(&git_args),
+            ) {
+                println!("git {} {}", command, reason);
+                // a script can't tell "Aborted" from success by the output
+                if !confirm("Run it?")? {
+                    return Err("aborted, git was not run".into());
+                }
+            }
+            let mut run = run_git_command(&args, &git_args)?;
+            // passed through as bytes: git output needn't be UTF-8
+            let mut stdout = std::io::stdout().lock();
+            std::io::Write::write_all(&mut stdout, &run.output.stdout)?;
+            std::io::Write::write_all(&mut stdout, b"\n")?;
+            std::io::Write::flush(&mut stdout)?;
+            record_git_command(&args, &git_args, &mut run)?;
+            if let (true, Some(timeout)) = (run.timed_out, args.timeout) {
+                return Err(format!("git timed out after {}s", timeout).into());
+            }
+        }
+        None => {
+            println!("No subcommand was used");
+        }
+    }
+    Ok(())
+}
+
+/// A git command that has run but may not have been recorded yet.
+struct GitRun {
+    output: std::process::Output,
+    /// Whether git was killed for running past --timeout.
+    timed_out: bool,
+    /// What recording needs from before git ran; None when the command is
+    /// filtered out and won't be recorded.
+    pending: Option<PendingRecord>,
+}
+
+struct PendingRecord {
+    before: PreCommandState,
+    rebase_todo_copy: Option<std::path::PathBuf>,
+    profile: Profile,
+}
+
+// Runs a git command, capturing what's needed to record it afterwards with
+// record_git_command. The two are separate so callers can pass git's output
+// on before the database is touched, and it isn't lost if recording fails.
+fn run_git_command(
+    args: &GitHistoryWrapper,
+    git_args: &[String],
+) -> Result<GitRun, Box<dyn std::error::Error>> {
+    let command = git_args.join(" ");
+    let timeout = args.timeout.map(std::time::Duration::from_secs);
+    if args.record_only_mutating
+        && !GitCommandState::extract_git_command(&command)
+            .is_ok_and(|git_command| invocation_is_mutate(git_command, git_args))
+    {
+        log::debug!("not recording read-only command");
+        let (output, timed_out) = run_git(git_args, timeout).map_err(GithistError::GitFailed)?;
+        return Ok(GitRun {
+            output,
+            timed_out,
+            pending: None,
+        });
+    }
+    let rebase_todo_copy = match GitCommandState::extract_git_command(&command) {
+        Ok(GitCommand::Rebase)
+            if normalize_args(&GitCommand::Rebase, git_args).contains(&"--interactive".into()) =>
+        {
+            match intercept_sequence_editor() {
+                Ok(copy) => Some(copy),
+                Err(err) => {
+                    log::warn!("not keeping the rebase todo list: {}", err);
+                    None
+                }
+            }
+        }
+        _ => None,
+    };
+    let mut profile = Profile::default();
+    let before = profile
+        .time("capture before", || PreCommandState::capture(&command))
+        .map_err(GithistError::GitFailed)?;
+    log::debug!("running git {:?}", git_args);
+    let (output, timed_out) = profile
+        .time("git", || run_git(git_args, timeout))
+        .map_err(GithistError::GitFailed)?;
+    log::debug!("git exited with {}", output.status);
+    Ok(GitRun {
+        output,
+        timed_out,
+        pending: Some(PendingRecord {
+            before,
+            rebase_todo_copy,
+            profile,
+        }),
+    })
+}
+
+fn record_git_command(
+    args: &GitHistoryWrapper,
+    git_args: &[String],
+    run: &mut GitRun,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(PendingRecord {
+        before,
+        rebase_todo_copy,
+        mut profile,
+    }) = run.pending.take()
+    else {
+        return Ok(());
+    };
+    let conn = profile.time("db open", open_history_db)?;
+    let mut state = profile.time("collect state", || {
+        GitCommandState::new(git_args, args.canonicalize, before, run.output.status)
+    });
+    state.timed_out = run.timed_out;
+    state.truncate_affected_files(args.max_affected_files);
+    if matches!(state.command, GitCommand::Bisect) {
+        state.session_id = bisect_session_id(&conn, git_args)?;
+    }
+    if let Some(path) = rebase_todo_copy {
+        state.rebase_todo = read_rebase_todo(&path);
+    }
+    profile.time("record", || {
+        add_command_history(&conn, state, args.mirror_jsonl)
+    })?;
+    if args.profile {
+        profile.print();
+    }
+    if let Some(max_db_size) = args.max_db_size {
+        let evicted = prune_to_size(&conn, max_db_size)?;
+        log::debug!("database size check evicted {} rows", evicted);
+        if evicted > 0 {
+            eprintln!(
+                "githist: evicted {} rows to keep the history under {} bytes",
+                evicted, max_db_size
+            );
+        }
+    }
+    Ok(())
+}
+
+// Points git at a sequence editor that runs the user's own editor and then
+// keeps a copy of the edited todo list, returning where the copy will be.
+// git runs the editor through the shell with the todo path appended.
+//
+// The copy goes in a new directory with a random name that only we can
+// write to: `cp` follows symlinks, so a guessable path in a shared temp
+// directory would let another user aim it at any of our files.
+fn intercept_sequence_editor() -> std::io::Result<std::path::PathBuf> {
+    let dir = std::env::temp_dir().join(format!("githist-rebase-todo-{}", Uuid::new_v4()));
+    let mut builder = std::fs::DirBuilder::new();
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    builder.create(&dir)?;
+    let copy = dir.join("git-rebase-todo");
+    let editor = std::env::var("GIT_SEQUENCE_EDITOR")
+        .ok()
+        .or_else(|| get_git_config("sequence.editor"))
+        .or_else(get_git_editor)
+        .unwrap_or_else(|| "vi".to_string());
+    std::env::set_var("GITHIST_SEQUENCE_EDITOR", editor);
+    std::env::set_var("GITHIST_REBASE_TODO", &copy);
+    std::env::set_var(
+        "GIT_SEQUENCE_EDITOR",
+        r#"githist_edit() { eval "$GITHIST_SEQUENCE_EDITOR \"\$1\"" && cp "$1" "$GITHIST_REBASE_TODO"; }; githist_edit"#,
+    );
+    Ok(copy)
+}
+
+// Missing when the editor failed, or never ran.
+fn read_rebase_todo(path: &std::path::Path) -> Option<Vec<String>> {
+    let todo = std::fs::read_to_string(path);
+    if let Some(dir) = path.parent() {
+        if let Err(err) = std::fs::remove_dir_all(dir) {
+            log::warn!("could not remove {}: {}", dir.display(), err);
+        }
+    }
+    Some(
+        todo.ok()?
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_string)
+            .collect(),
+    )
+}
+
+// Runs git to completion, or kills it once `timeout` elapses. The returned
+// flag says whether it was killed. Output is drained on separate threads so
+// a chatty child can't block on a full pipe while we wait on it.
+//
+// git stays in the terminal's process group, so editors, credential and
+// passphrase prompts can still read the TTY and Ctrl-C still reaches it.
+// That means only git itself is killed on expiry; helpers it started (ssh,
+// remote helpers) may outlive it holding the pipes, so after a kill we only
+// wait briefly for the rest of the output.
+fn run_git(
+    args: &[String],
+    timeout: Option<std::time::Duration>,
+) -> std::io::Result<(std::process::Output, bool)> {
+    use std::io::Read;
+    let Some(timeout) = timeout else {
+        let output = git().args(args).output()?;
+        return Ok((output, false));
+    };
+    let mut command = git();
+    command
+        .args(args)
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped());
+    let mut child = command.spawn()?;
+    let drain = |pipe: Option<Box<dyn Read + Send>>| {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let mut buf = vec![];
+            if let Some(mut pipe) = pipe {
+                let _ = pipe.read_to_end(&mut buf);
+            }
+            let _ = sender.send(buf);
+        });
+        receiver
+    };
+    let stdout = drain(child.stdout.take().map(|pipe| Box::new(pipe) as _));
+    let stderr = drain(child.stderr.take().map(|pipe| Box::new(pipe) as _));
+    let deadline = std::time::Instant::now() + timeout;
+    let mut timed_out = false;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if std::time::Instant::now() >= deadline {
+            log::warn!("git did not finish within {:?}, killing it", timeout);
+            child.kill()?;
+            timed_out = true;
+            break child.wait()?;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    };
+    let grace = std::time::Instant::now() + std::time::Duration::from_secs(1);
+    let collect = |receiver: std::sync::mpsc::Receiver<Vec<u8>>| {
+        if timed_out {
+            receiver
+                .recv_timeout(grace.saturating_duration_since(std::time::Instant::now()))
+                .unwrap_or_default()
+        } else {
+            receiver.recv().unwrap_or_default()
+        }
+    };
+    let output = std::process::Output {
+        status,
+        stdout: collect(stdout),
+        stderr: collect(stderr),
+    };
+    Ok((output, timed_out))
+}
+
+// Concurrent first runs would otherwise race between creating the table
+// and any later schema steps. BEGIN IMMEDIATE takes the write lock up
+// front, so one initializer finishes while the other waits on the busy
+// timeout and then finds the schema already in place.
+fn init_history_table(conn: &mut rusqlite::Connection) -> Result<(), Box<dyn std::error::Error>> {
+    conn.busy_timeout(std::time::Duration::from_secs(10))?;
+    let tx = conn.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+    create_history_table(&tx)?;
+    migrate_history_table(&tx)?;
+    tx.commit()?;
+    log::info!("ensured git_command_history table exists");
+    Ok(())
+}
+
+// Opens the history database for any command that reads or writes rows,
+// first bringing a table left by an older version up to the current schema,
+// since queries and inserts name every column.
+fn open_history_db() -> Result<rusqlite::Connection, Box<dyn std::error::Error>> {
+    let mut conn = rusqlite::Connection::open(GIT_COMMAND_HISTORY_FILE_PATH)?;
+    let columns = history_table_columns(&conn)?;
+    // no columns means no table, which the query itself will report
+    let outdated = !columns.is_empty()
+        && HISTORY_COLUMNS
+            .iter()
+            .any(|column| !columns.iter().any(|name| name == column));
+    if outdated {
+        log::info!("history table predates the current schema, migrating it");
+        init_history_table(&mut conn)?;
+    }
+    Ok(conn)
+}
+
+fn create_history_table(conn: &rusqlite::Connection) -> Result<(), Box<dyn std::error::Error>> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS git_command_history (
+            id TEXT PRIMARY KEY,
+            command TEXT NOT NULL,
+            created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            checksum TEXT,
+            command_kind TEXT,
+            repo_root TEXT
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+fn history_table_columns(conn: &rusqlite::Connection) -> rusqlite::Result<Vec<String>> {
+    conn.prepare("SELECT name FROM pragma_table_info('git_command_history')")?
+        .query_map([], |row| row.get(0))?
+        .collect()
+}
+
+// Brings tables created by older versions up to the current schema.
+fn migrate_history_table(conn: &rusqlite::Connection) -> Result<(), Box<dyn std::error::Error>> {
+    let columns = history_table_columns(conn)?;
+    if !columns.iter().any(|column| column == "checksum") {
+        conn.execute(
+            "ALTER TABLE git_command_history ADD COLUMN checksum TEXT",
+            [],
+        )?;
+        log::info!("added checksum column");
+    }
+    if !columns.iter().any(|column| column == "command_kind") {
+        conn.execute(
+            "ALTER TABLE git_command_history ADD COLUMN command_kind TEXT",
+            [],
+        )?;
+        let backfilled = conn.execute(
+            "UPDATE git_command_history SET command_kind = json_extract(command, '$.command')",
+            [],
+        )?;
+        log::info!("added command_kind column, backfilled {} rows", backfilled);
+    }
+    if !columns.iter().any(|column| column == "repo_root") {
+        conn.execute(
+            "ALTER TABLE git_command_history ADD COLUMN repo_root TEXT",
+            [],
+        )?;
+        // older rows don't know their repository and stay null
+        conn.execute(
+            "UPDATE git_command_history SET repo_root = json_extract(command, '$.repo_root')",
+            [],
+        )?;
+        log::info!("added repo_root column");
+    }
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS git_command_history_repo_root
+        ON git_command_history (repo_root)",
+        [],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS git_command_history_command_kind
+        ON git_command_history (command_kind)",
+        [],
+    )?;
+    Ok(())
+}
+
+// Hashes the stored text rather than a re-serialization, so fields added
+// to GitCommandState later don't invalidate older rows. Uses git's blob
+// hashing since git2 is already on hand.
+fn row_checksum(command: &str, created_at: &str) -> Result<String, git2::Error> {
+    let content = format!("{}\n{}", command, created_at);
+    Ok(git2::Oid::hash_object(git2::ObjectType::Blob, content.as_bytes())?.to_string())
+}
+
+// Rebuilds the table from the JSONL mirror, returning how many rows were
+// restored and how many mirror lines were skipped as malformed.
+fn restore_from_mirror(
+    conn: &mut rusqlite::Connection,
+) -> Result<(usize, usize), Box<dyn std::error::Error>> {
+    let mirror = std::fs::read_to_string(GIT_COMMAND_HISTORY_MIRROR_PATH)?;
+    let tx = conn.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+    tx.execute("DROP TABLE IF EXISTS git_command_history", [])?;
+    create_history_table(&tx)?;
+    migrate_history_table(&tx)?;
+    let mut restored = 0;
+    let mut skipped = 0;
+    for line in mirror.lines().filter(|line| !line.trim().is_empty()) {
+        let Ok(record) = serde_json::from_str::<MirrorRecord>(line) else {
+            skipped += 1;
+            continue;
+        };
+        let command = serde_json::to_string(&record.command)?;
+        restored += tx.execute(
+            "INSERT OR REPLACE INTO git_command_history
+            (id, command, created_at, checksum, command_kind, repo_root)
+            VALUES (?1, ?2, ?3, ?4, json_extract(?2, '$.command'), json_extract(?2, '$.repo_root'))",
+            [
+                &record.id,
+                &command,
+                &record.created_at,
+                &row_checksum(&command, &record.created_at)?,
+            ],
+        )?;
+    }
+    tx.commit()?;
+    Ok((restored, skipped))
+}
+
+fn add_command_history(
+    conn: &rusqlite::Connection,
+    command: GitCommandState,
+    mirror_jsonl: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let record = MirrorRecord {
+        id: Uuid::new_v4().to_string(),
+        created_at: time::OffsetDateTime::now_utc().to_string(),
+        command,
+    };
+    let command = serde_json::to_string(&record.command)?;
+    conn.execute(
+        "INSERT INTO git_command_history
+        (id, command, created_at, checksum, command_kind, repo_root)
+        VALUES (?1, ?2, ?3, ?4, json_extract(?2, '$.command'), json_extract(?2, '$.repo_root'))",
+        [
+            &record.id,
+            &command,
+            &record.created_at,
+            &row_checksum(&command, &record.created_at)?,
+        ],
+    )?;
+    log::info!(
+        "recorded {} as {}",
+        serde_json::to_string(&record.command.command)?,
+        record.id
+    );
+    if mirror_jsonl {
+        // the database is the source of truth, so a failed mirror write
+        // shouldn't fail the command
+        if let Err(err) = append_to_mirror(&record) {
+            eprintln!("githist: failed to write history mirror: {}", err);
+        }
+    }
+    if let Some(hook) = std::env::var_os(POST_RECORD_HOOK_ENV) {
+        if let Err(err) = run_post_record_hook(&hook, &record) {
+            log::warn!("{} hook failed: {}", POST_RECORD_HOOK_ENV, err);
+        }
+    }
+    Ok(())
+}
+
+// Starts the hook and hands it the record, without waiting for it to read
+// it or exit. Its stdin is an already written temporary file rather than a
+// pipe: a record bigger than the pipe buffer would block us until the hook
+// read it, and a writer thread would be cut off when githist exits.
+fn run_post_record_hook(
+    hook: &std::ffi::OsStr,
+    record: &MirrorRecord,
+) -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::{Seek, Write};
+    let path = std::env::temp_dir().join(format!("githist-record-{}", record.id));
+    let mut file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    // the open handle keeps the contents around for the hook
+    if let Err(err) = std::fs::remove_file(&path) {
+        log::debug!("could not remove {}: {}", path.display(), err);
+    }
+    writeln!(file, "{}", serde_json::to_string(record)?)?;
+    file.rewind()?;
+    let child = std::process::Command::new(hook).stdin(file).spawn()?;
+    log::debug!("started post-record hook as pid {}", child.id());
+    Ok(())
+}
+
+/// One line of the `.git_command_history.jsonl` mirror.
+#[derive(Serialize, Deserialize)]
+struct MirrorRecord {
+    id: String,
+    created_at: String,
+    command: GitCommandState,
+}
+
+fn append_to_mirror(record: &MirrorRecord) -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::Write;
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(GIT_COMMAND_HISTORY_MIRROR_PATH)?;
+    writeln!(file, "{}", serde_json::to_string(record)?)?;
+    Ok(())
+}
+
+// `bisect start` opens a new session; later bisect steps join the most
+// recent one unless it has already been reset.
+fn bisect_session_id(
+    conn: &rusqlite::Connection,
+    git_args: &[String],
+) -> rusqlite::Result<Option<String>> {
+    use rusqlite::OptionalExtension;
+    if git_args.get(1).map(String::as_str) == Some("start") {
+        return Ok(Some(Uuid::new_v4().to_string()));
+    }
+    let latest = conn
+        .query_row(
+            "SELECT json_extract(command, '$.session_id'), json_extract(command, '$.args[1]')
+            FROM git_command_history
+            WHERE command_kind = 'bisect'
+            ORDER BY rowid DESC LIMIT 1",
+            [],
+            |row| {
+                Ok((
+                    row.get::<_, Option<String>>(0)?,
+                    row.get::<_, Option<String>>(1)?,
+                ))
+            },
+        )
+        .optional()?;
+    Ok(match latest {
+        Some((session_id, step)) if step.as_deref() != Some("reset") => session_id,
+        _ => None,
+    })
+}
+
+// Restricts a query to the repository bound as ?1, or to nothing when it is
+// null. Rows recorded before repo_root existed belong to an unknown
+// repository and are always kept.
+const REPO_FILTER: &str = "(?1 IS NULL OR repo_root IS NULL OR repo_root = ?1)";
+
+// The repository to show history for: the current one, unless all
+// repositories were asked for or we aren't in one.
+fn repo_filter(all_repos: bool) -> Option<String> {
+    if all_repos {
+        return None;
+    }
+    get_repo_common_root().map(|root| root.display().to_string())
+}
+
+// Quotes an argument for bash, leaving plainly safe words alone.
+fn shell_quote(arg: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "_@%+=:,./-".contains(c);
+    if !arg.is_empty() && arg.chars().all(safe) {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+// Rows recorded before arguments were stored, and snapshots, have nothing
+// to run and are left out. The script is meant to run from where it was
+// exported, so commands recorded in another directory, e.g. under -C, get
+// a `-C` of their own.
+fn print_script(
+    conn: &rusqlite::Connection,
+    commands: &[String],
+    branch: Option<&str>,
+    tz: DisplayTimezone,
+    repo_root: Option<String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // one placeholder per kind after the repository's ?1, so the lookup can
+    // use the command_kind index
+    let kind_filter = if commands.is_empty() {
+        String::new()
+    } else {
+        let placeholders: Vec<String> = (2..commands.len() + 2)
+            .map(|index| format!("?{}", index))
+            .collect();
+        format!(" AND command_kind IN ({})", placeholders.join(", "))
+    };
+    let mut stmt = conn.prepare(&format!(
+        "SELECT command, created_at FROM git_command_history
+        WHERE {}{} ORDER BY rowid",
+        REPO_FILTER, kind_filter
+    ))?;
+    let mut rows = stmt.query(rusqlite::params_from_iter(
+        std::iter::once(repo_root).chain(commands.iter().cloned().map(Some)),
+    ))?;
+    let here = command_dir().ok().map(|dir| dir.display().to_string());
+    println!("#!/usr/bin/env bash");
+    println!("set -e");
+    while let Some(row) = rows.next()? {
+        let state: GitCommandState = serde_json::from_str(&row.get::<_, String>(0)?)?;
+        let state_branch = state.current_branch.trim_end();
+        if state.args.is_empty() || branch.is_some_and(|branch| branch != state_branch) {
+            continue;
+        }
+        println!();
+        println!(
+            "# {} on {}",
+            tz.format_created_at(&row.get::<_, String>(1)?),
+            state_branch
+        );
+        let mut args: Vec<String> = state.args.iter().map(|arg| shell_quote(arg)).collect();
+        if let Some(cwd) = state.cwd.as_ref().filter(|&cwd| Some(cwd) != here.as_ref()) {
+            args.splice(0..0, ["-C".to_string(), shell_quote(cwd)]);
+        }
+        println!("git {}", args.join(" "));
+    }
+    Ok(())
+}
+
+struct Session {
+    start: time::OffsetDateTime,
+    end: time::OffsetDateTime,
+    commands: usize,
+    branches: Vec<String>,
+}
+
+// Rows whose created_at doesn't parse can't be placed in time and are left
+// out.
+fn print_sessions(
+    conn: &rusqlite::Connection,
+    gap: time::Duration,
+    tz: DisplayTimezone,
+    repo_root: Option<String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT command, created_at FROM git_command_history WHERE {} ORDER BY rowid",
+        REPO_FILTER
+    ))?;
+    let mut rows = stmt.query([repo_root])?;
+    let mut sessions: Vec<Session> = vec![];
+    while let Some(row) = rows.next()? {
+        let Some(at) = parse_created_at(&row.get::<_, String>(1)?) else {
+            continue;
+        };
+        let state: GitCommandState = serde_json::from_str(&row.get::<_, String>(0)?)?;
+        let branch = state.current_branch.trim_end().to_string();
+        match sessions.last_mut() {
+            Some(session) if at - session.end <= gap => {
+                session.end = at;
+                session.commands += 1;
+                if !branch.is_empty() && !session.branches.contains(&branch) {
+                    session.branches.push(branch);
+                }
+            }
+            _ => sessions.push(Session {
+                start: at,
+                end: at,
+                commands: 1,
+                branches: [branch]
+                    .into_iter()
+                    .filter(|name| !name.is_empty())
+                    .collect(),
+            }),
+        }
+    }
+    for session in &sessions {
+        println!(
+            "{}  {:>7}  {:>4} commands  {}",
+            tz.format_created_at(&session.start.to_string()),
+            format_duration(session.end - session.start),
+            session.commands,
+            session.branches.join(", ")
+        );
+    }
+    Ok(())
+}
+
+enum BranchChange {
+    Created,
+    Deleted,
+}
+
+// The branches a recorded checkout, switch or branch command created or
+// deleted, read from its normalized arguments. Rows from before arguments
+// were stored have none.
+fn branch_changes(state: &GitCommandState) -> Vec<(BranchChange, String)> {
+    // options that leave `git branch <name>` creating a branch
+    const CREATE_OPTIONS: &[&str] = &[
+        "--force",
+        "--track",
+        "--no-track",
+        "-t",
+        "--quiet",
+        "--create-reflog",
+        "--recurse-submodules",
+    ];
+    let args = state.normalized_args.get(1..).unwrap_or_default();
+    let value_of = |options: &[&str]| {
+        args.windows(2)
+            .find(|pair| options.contains(&pair[0].as_str()))
+            .map(|pair| pair[1].clone())
+    };
+    let positionals = || {
+        args.iter()
+            .filter(|arg| !arg.starts_with('-'))
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+    match state.command {
+        // -b and -B have no alias, so they can still be bundled with the name
+        GitCommand::Checkout => value_of(&["-b", "-B", "--orphan"])
+            .or_else(|| {
+                args.iter().find_map(|arg| {
+                    arg.strip_prefix("-b")
+                        .or_else(|| arg.strip_prefix("-B"))
+                        .filter(|name| !name.is_empty())
+                        .map(str::to_string)
+                })
+            })
+            .map(|name| (BranchChange::Created, name))
+            .into_iter()
+            .collect(),
+        GitCommand::Switch => value_of(&["--create", "--force-create", "--orphan"])
+            .map(|name| (BranchChange::Created, name))
+            .into_iter()
+            .collect(),
+        GitCommand::Branch if args.iter().any(|arg| arg == "--delete" || arg == "-D") => {
+            positionals()
+                .into_iter()
+                .map(|name| (BranchChange::Deleted, name))
+                .collect()
+        }
+        GitCommand::Branch
+            if args.iter().all(|arg| {
+                !arg.starts_with('-')
+                    || CREATE_OPTIONS.contains(&arg.as_str())
+                    || arg.starts_with("--track=")
+            }) =>
+        {
+            positionals()
+                .into_iter()
+                .take(1)
+                .map(|name| (BranchChange::Created, name))
+                .collect()
+        }
+        _ => vec![],
+    }
+}
+
+fn print_branch_lifetime(
+    conn: &rusqlite::Connection,
+    branch: &str,
+    tz: DisplayTimezone,
+    repo_root: Option<String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT command, created_at FROM git_command_history WHERE {} ORDER BY rowid",
+        REPO_FILTER
+    ))?;
+    let mut rows = stmt.query([repo_root])?;
+    let mut runs = vec![];
+    let mut changes = vec![];
+    while let Some(row) = rows.next()? {
+        let state: GitCommandState = serde_json::from_str(&row.get::<_, String>(0)?)?;
+        let created_at: String = row.get(1)?;
+        for (change, name) in branch_changes(&state) {
+            if name == branch {
+                changes.push((change, state.args.join(" "), created_at.clone()));
+            }
+        }
+        if state.current_branch.trim_end() == branch {
+            runs.push((state.command, created_at));
+        }
+    }
+    if runs.is_empty() && changes.is_empty() {
+        return Err(GithistError::NotFound(format!("history for branch `{}`", branch)).into());
+    }
+    println!("branch {}", branch);
+    for (change, command, at) in &changes {
+        let label = match change {
+            BranchChange::Created => "created",
+            BranchChange::Deleted => "deleted",
+        };
+        println!("{}: {} git {}", label, tz.format_created_at(at), command);
+    }
+    if let (Some((first_command, first_at)), Some((last_command, last_at))) =
+        (runs.first(), runs.last())
+    {
+        println!(
+            "first:  {} {}",
+            tz.format_created_at(first_at),
+            serde_json::to_string(first_command)?
+        );
+        println!(
+            "last:   {} {}",
+            tz.format_created_at(last_at),
+            serde_json::to_string(last_command)?
+        );
+        if let (Some(first), Some(last)) = (parse_created_at(first_at), parse_created_at(last_at)) {
+            println!(
+                "active: {} over {} commands",
+                format_duration(last - first),
+                runs.len()
+            );
+        }
+    }
+    // a recorded deletion settles it; otherwise ask the repository, which
+    // also catches deletions made without githist
+    let status = match changes.last() {
+        Some((BranchChange::Deleted, _, _)) => "deleted",
+        _ => match branch_exists(branch) {
+            Some(true) => "still exists",
+            Some(false) => "deleted",
+            None => "unknown (not in a git repository)",
+        },
+    };
+    println!("status: {}", status);
+    Ok(())
+}
+
+// Returns the ids of rows whose content no longer matches their checksum and
+// the number of rows written before checksums existed.
+fn verify_checksums(
+    conn: &rusqlite::Connection,
+) -> Result<(Vec<String>, usize), Box<dyn std::error::Error>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, command, created_at, checksum FROM git_command_history ORDER BY rowid",
+    )?;
+    let mut rows = stmt.query([])?;
+    let mut mismatched = vec![];
+    let mut unchecked = 0;
+    while let Some(row) = rows.next()? {
+        let Some(checksum) = row.get::<_, Option<String>>(3)? else {
+            unchecked += 1;
+            continue;
+        };
+        let command: String = row.get(1)?;
+        let created_at: String = row.get(2)?;
+        if row_checksum(&command, &created_at)? != checksum {
+            mismatched.push(row.get(0)?);
+        }
+    }
+    Ok((mismatched, unchecked))
+}
+
+enum CheckStatus {
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl std::fmt::Display for CheckStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CheckStatus::Pass => write!(f, "pass"),
+            CheckStatus::Warn => write!(f, "warn"),
+            CheckStatus::Fail => write!(f, "fail"),
+        }
+    }
+}
+
+enum FixStatus {
+    Applied,
+    Skipped,
+}
+
+impl std::fmt::Display for FixStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FixStatus::Applied => write!(f, "applied"),
+            FixStatus::Skipped => write!(f, "skipped"),
+        }
+    }
+}
+
+const HISTORY_COLUMNS: &[&str] = &[
+    "id",
+    "command",
+    "created_at",
+    "checksum",
+    "command_kind",
+    "repo_root",
+];
+
+const HISTORY_INDEXES: &[&str] = &[
+    "git_command_history_command_kind",
+    "git_command_history_repo_root",
+];
+
+// Repairs that can't lose history. Anything destructive, like restore-db,
+// stays a separate command behind its own confirmation.
+fn doctor_fix() -> Result<Vec<(FixStatus, String)>, Box<dyn std::error::Error>> {
+    if !std::path::Path::new(GIT_COMMAND_HISTORY_FILE_PATH).exists() {
+        return Ok(vec![(
+            FixStatus::Skipped,
+            format!(
+                "{} does not exist, run `command-history-init`",
+                GIT_COMMAND_HISTORY_FILE_PATH
+            ),
+        )]);
+    }
+    let mut fixes = vec![];
+    let mut conn = rusqlite::Connection::open(GIT_COMMAND_HISTORY_FILE_PATH)?;
+    let columns = history_table_columns(&conn)?;
+    let indexes: Vec<String> = conn
+        .prepare("SELECT name FROM pragma_index_list('git_command_history')")?
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+    let up_to_date = HISTORY_COLUMNS
+        .iter()
+        .all(|column| columns.iter().any(|name| name == column))
+        && HISTORY_INDEXES
+            .iter()
+            .all(|index| indexes.iter().any(|name| name == index));
+    if up_to_date {
+        fixes.push((FixStatus::Skipped, "schema is up to date".to_string()));
+    } else {
+        init_history_table(&mut conn)?;
+        fixes.push((
+            FixStatus::Applied,
+            "migrated the schema and created missing indexes".to_string(),
+        ));
+    }
+    let mode: String = conn.query_row("PRAGMA journal_mode", [], |row| row.get(0))?;
+    if mode.eq_ignore_ascii_case("wal") {
+        fixes.push((FixStatus::Skipped, "WAL journaling is enabled".to_string()));
+    } else {
+        conn.query_row("PRAGMA journal_mode = WAL", [], |_| Ok(()))?;
+        fixes.push((
+            FixStatus::Applied,
+            format!("switched journal mode from {} to wal", mode),
+        ));
+    }
+    let pages: u64 = conn.query_row("PRAGMA page_count", [], |row| row.get(0))?;
+    let free_pages: u64 = conn.query_row("PRAGMA freelist_count", [], |row| row.get(0))?;
+    // a fifth of the file being free pages is worth a rewrite
+    if free_pages * 5 > pages {
+        conn.execute("VACUUM", [])?;
+        conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+        fixes.push((
+            FixStatus::Applied,
+            format!("vacuumed {} free pages of {}", free_pages, pages),
+        ));
+    } else {
+        fixes.push((
+            FixStatus::Skipped,
+            format!(
+                "{} of {} pages are free, no vacuum needed",
+                free_pages, pages
+            ),
+        ));
+    }
+    Ok(fixes)
+}
+
+// Every check is read-only; the database is opened read-only so a missing
+// file is reported rather than created.
+fn doctor() -> Vec<(CheckStatus, String)> {
+    let mut checks = vec![];
+    match git().arg("--version").output() {
+        Ok(output) if output.status.success() => checks.push((
+            CheckStatus::Pass,
+            String::from_utf8_lossy(&output.stdout)
+                .trim_end()
+                .to_string(),
+        )),
+        Ok(_) => checks.push((CheckStatus::Fail, "git --version failed".to_string())),
+        Err(err) => checks.push((CheckStatus::Fail, format!("git is not on PATH: {}", err))),
+    }
+    if !std::path::Path::new(GIT_COMMAND_HISTORY_FILE_PATH).exists() {
+        checks.push((
+            CheckStatus::Fail,
+            format!(
+                "{} does not exist, run `command-history-init`",
+                GIT_COMMAND_HISTORY_FILE_PATH
+            ),
+        ));
+        return checks;
+    }
+    let conn = match rusqlite::Connection::open_with_flags(
+        GIT_COMMAND_HISTORY_FILE_PATH,
+        rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY,
+    ) {
+        Ok(conn) => conn,
+        Err(err) => {
+            checks.push((CheckStatus::Fail, format!("cannot open database: {}", err)));
+            return checks;
+        }
+    };
+    checks.push((
+        CheckStatus::Pass,
+        format!("{} exists", GIT_COMMAND_HISTORY_FILE_PATH),
+    ));
+    match history_table_columns(&conn) {
+        Ok(columns) if columns.is_empty() => checks.push((
+            CheckStatus::Fail,
+            "git_command_history table is missing, run `command-history-init`".to_string(),
+        )),
+        Ok(columns) => {
+            let missing: Vec<&str> = HISTORY_COLUMNS
+                .iter()
+                .copied()
+                .filter(|column| !columns.iter().any(|name| name == column))
+                .collect();
+            if missing.is_empty() {
+                checks.push((CheckStatus::Pass, "schema is up to date".to_string()));
+            } else {
+                checks.push((
+                    CheckStatus::Fail,
+                    format!(
+                        "schema is missing columns: {}, run `command-history-init`",
+                        missing.join(", ")
+                    ),
+                ));
+            }
+        }
+        Err(err) => checks.push((CheckStatus::Fail, format!("cannot read schema: {}", err))),
+    }
+    match conn.query_row("PRAGMA journal_mode", [], |row| row.get::<_, String>(0)) {
+        Ok(mode) if mode.eq_ignore_ascii_case("wal") => {
+            checks.push((CheckStatus::Pass, "WAL journaling is enabled".to_string()))
+        }
+        Ok(mode) => checks.push((
+            CheckStatus::Warn,
+            format!("journal mode is {}, not wal", mode),
+        )),
+        Err(err) => checks.push((
+            CheckStatus::Warn,
+            format!("cannot read journal mode: {}", err),
+        )),
+    }
+    checks
+}
+
+// Resolves a full id or unique id prefix to its (id, command, created_at) row.
+fn find_record(
+    conn: &rusqlite::Connection,
+    id: &str,
+) -> Result<(String, String, String), Box<dyn std::error::Error>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, command, created_at FROM git_command_history
+        WHERE substr(id, 1, length(?1)) = ?1 LIMIT 2",
+    )?;
+    let mut rows = stmt
+        .query_map([id], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+        .collect::<Result<Vec<_>, _>>()?;
+    match rows.len() {
+        0 => Err(GithistError::NotFound(format!("record `{}`", id)).into()),
+        1 => Ok(rows.remove(0)),
+        _ => Err(format!("id prefix `{}` matches more than one record", id).into()),
+    }
+}
+
+fn confirm(prompt: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    use std::io::Write;
+    print!("{} [y/N] ", prompt);
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+fn nuke_file(
+    conn: &mut rusqlite::Connection,
+    path: &str,
+    delete_rows: bool,
+) -> Result<(usize, usize), Box<dyn std::error::Error>> {
+    let tx = conn.transaction()?;
+    let mut rewritten = 0;
+    let mut deleted = 0;
+    {
+        let mut stmt = tx.prepare("SELECT id, command, created_at FROM git_command_history")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let id: String = row.get(0)?;
+            let mut state: GitCommandState = serde_json::from_str(&row.get::<_, String>(1)?)?;
+            let created_at: String = row.get(2)?;
+            if !state.scrub_path(path) {
+                continue;
+            }
+            if delete_rows {
+                deleted += tx.execute("DELETE FROM git_command_history WHERE id = ?1", [&id])?;
+            } else {
+                let command = serde_json::to_string(&state)?;
+                let checksum = row_checksum(&command, &created_at)?;
+                rewritten += tx.execute(
+                    "UPDATE git_command_history SET command = ?1, checksum = ?2 WHERE id = ?3",
+                    [command, checksum, id],
+                )?;
+            }
+        }
+    }
+    tx.commit()?;
+    Ok((rewritten, deleted))
+}
+
+// Does to the mirror what nuke_file does to the table, returning how many
+// lines were rewritten or dropped, or None when there is no mirror. Lines
+// that don't parse are kept as they are, as restore-db skips them anyway.
+fn nuke_file_in_mirror(
+    path: &str,
+    delete_rows: bool,
+) -> Result<Option<usize>, Box<dyn std::error::Error>> {
+    let mirror = match std::fs::read_to_string(GIT_COMMAND_HISTORY_MIRROR_PATH) {
+        Ok(mirror) => mirror,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let mut changed = 0;
+    let mut scrubbed = String::new();
+    for line in mirror.lines() {
+        let Ok(mut record) = serde_json::from_str::<MirrorRecord>(line) else {
+            scrubbed.push_str(line);
+            scrubbed.push('\n');
+            continue;
+        };
+        if !record.command.scrub_path(path) {
+            scrubbed.push_str(line);
+            scrubbed.push('\n');
+            continue;
+        }
+        changed += 1;
+        if !delete_rows {
+            scrubbed.push_str(&serde_json::to_string(&record)?);
+            scrubbed.push('\n');
+        }
+    }
+    // replace the file in one step so a failure can't leave half a mirror
+    let tmp = format!("{}.tmp", GIT_COMMAND_HISTORY_MIRROR_PATH);
+    std::fs::write(&tmp, scrubbed)?;
+    std::fs::rename(&tmp, GIT_COMMAND_HISTORY_MIRROR_PATH)?;
+    Ok(Some(changed))
+}
+
+// Size of the database file plus its write-ahead log, if there is one.
+fn database_size() -> std::io::Result<u64> {
+    let wal = std::fs::metadata(format!("{}-wal", GIT_COMMAND_HISTORY_FILE_PATH))
+        .map_or(0, |metadata| metadata.len());
+    Ok(std::fs::metadata(GIT_COMMAND_HISTORY_FILE_PATH)?.len() + wal)
+}
+
+// The file only shrinks once it is vacuumed, so rather than deleting a row
+// at a time we estimate how many of the oldest rows make up the excess,
+// evict them, vacuum, and repeat until the file fits. In WAL mode VACUUM
+// writes the new file into the log, so it is checkpointed before measuring
+// again.
+fn prune_to_size(
+    conn: &rusqlite::Connection,
+    max_db_size: u64,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let mut evicted = 0;
+    loop {
+        let size = database_size()?;
+        if size <= max_db_size {
+            break;
+        }
+        let rows: u64 = conn.query_row("SELECT COUNT(*) FROM git_command_history", [], |row| {
+            row.get(0)
+        })?;
+        if rows == 0 {
+            break;
+        }
+        let excess_rows = ((size - max_db_size) * rows).div_ceil(size).max(1);
+        evicted += conn.execute(
+            "DELETE FROM git_command_history WHERE rowid IN (
+                SELECT rowid FROM git_command_history ORDER BY rowid LIMIT ?1
+            )",
+            [excess_rows],
+        )?;
+        conn.execute("VACUUM", [])?;
+        conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+    }
+    Ok(evicted)
+}
+
+fn command_creates_commit(command: &GitCommand) -> bool {
+    matches!(
+        command,
+        GitCommand::Commit
+            | GitCommand::CherryPick
+            | GitCommand::Merge
+            | GitCommand::Pull
+            | GitCommand::Rebase
+    )
+}
+
+/// A short flag, the long option it is shorthand for, and whether it takes
+/// a value (so `-mfoo` and `-m foo` both mean `--message foo`).
+type FlagAlias = (char, &'static str, bool);
+
+// Short/long pairs for the commands people run most. Only the value-taking
+// long options listed here have `--opt=value` split into `--opt value`;
+// extend a command's list to have more of its spellings group together.
+fn flag_aliases(command: &GitCommand) -> &'static [FlagAlias] {
+    match command {
+        GitCommand::Add => &[
+            ('A', "--all", false),
+            ('f', "--force", false),
+            ('n', "--dry-run", false),
+            ('p', "--patch", false),
+            ('u', "--update", false),
+            ('v', "--verbose", false),
+        ],
+        GitCommand::Branch => &[
+            ('a', "--all", false),
+            ('c', "--copy", false),
+            ('d', "--delete", false),
+            ('f', "--force", false),
+            ('l', "--list", false),
+            ('m', "--move", false),
+            ('r', "--remotes", false),
+            ('u', "--set-upstream-to", true),
+            ('v', "--verbose", false),
+        ],
+        GitCommand::Checkout => &[
+            ('f', "--force", false),
+            ('p', "--patch", false),
+            ('q', "--quiet", false),
+        ],
+        GitCommand::Clone => &[
+            ('b', "--branch", true),
+            ('j', "--jobs", true),
+            ('n', "--no-checkout", false),
+            ('o', "--origin", true),
+            ('q', "--quiet", false),
+            ('v', "--verbose", false),
+        ],
+        GitCommand::Commit => &[
+            ('a', "--all", false),
+            ('C', "--reuse-message", true),
+            ('c', "--reedit-message", true),
+            ('e', "--edit", false),
+            ('F', "--file", true),
+            ('m', "--message", true),
+            ('n', "--no-verify", false),
+            ('q', "--quiet", false),
+            ('s', "--signoff", false),
+            ('v', "--verbose", false),
+        ],
+        GitCommand::Fetch => &[
+            ('a', "--append", false),
+            ('f', "--force", false),
+            ('j', "--jobs", true),
+            ('p', "--prune", false),
+            ('q', "--quiet", false),
+            ('t', "--tags", false),
+            ('v', "--verbose", false),
+        ],
+        GitCommand::Merge => &[
+            ('F', "--file", true),
+            ('m', "--message", true),
+            ('n', "--no-stat", false),
+            ('q', "--quiet", false),
+            ('s', "--strategy", true),
+            ('v', "--verbose", false),
+            ('X', "--strategy-option", true),
+        ],
+        GitCommand::Pull => &[
+            ('q', "--quiet", false),
+            ('r', "--rebase", false),
+            ('v', "--verbose", false),
+        ],
+        GitCommand::Push => &[
+            ('d', "--delete", false),
+            ('f', "--force", false),
+            ('n', "--dry-run", false),
+            ('q', "--quiet", false),
+            ('u', "--set-upstream", false),
+            ('v', "--verbose", false),
+        ],
+        GitCommand::Rebase => &[
+            ('i', "--interactive", false),
+            ('q', "--quiet", false),
+            ('r', "--rebase-merges", false),
+            ('s', "--strategy", true),
+            ('v', "--verbose", false),
+            ('x', "--exec", true),
+            ('X', "--strategy-option", true),
+        ],
+        GitCommand::Reset => &[('p', "--patch", false), ('q', "--quiet", false)],
+        GitCommand::Restore => &[
+            ('p', "--patch", false),
+            ('q', "--quiet", false),
+            ('s', "--source", true),
+            ('S', "--staged", false),
+            ('W', "--worktree", false),
+        ],
+        GitCommand::Stash => &[
+            ('a', "--all", false),
+            ('k', "--keep-index", false),
+            ('m', "--message", true),
+            ('p', "--patch", false),
+            ('q', "--quiet", false),
+            ('u', "--include-untracked", false),
+        ],
+        GitCommand::Switch => &[
+            ('c', "--create", true),
+            ('C', "--force-create", true),
+            ('d', "--detach", false),
+            ('q', "--quiet", false),
+        ],
+        GitCommand::Tag => &[
+            ('a', "--annotate", false),
+            ('d', "--delete", false),
+            ('F', "--file", true),
+            ('f', "--force", false),
+            ('l', "--list", false),
+            ('m', "--message", true),
+            ('s', "--sign", false),
+            ('u', "--local-user", true),
+            ('v', "--verify", false),
+        ],
+        _ => &[],
+    }
+}
+
+/// Canonicalizes spellings of the same invocation so that, for example,
+/// `commit -am x`, `commit -a -m x` and `commit --all --message=x` all
+/// normalize to `commit --all --message x`.
+fn normalize_args(command: &GitCommand, args: &[String]) -> Vec<String> {
+    let aliases = flag_aliases(command);
+    let lookup = |flag: char| aliases.iter().find(|(short, _, _)| *short == flag);
+    let mut normalized = vec![];
+    let mut args = args.iter();
+    normalized.extend(args.next().cloned());
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            normalized.push(arg.clone());
+            normalized.extend(args.by_ref().cloned());
+        } else if arg.starts_with("--") {
+            // options whose value is optional mean something else without
+            // the `=`, e.g. `--untracked-files=no` versus `--untracked-files
+            // no`, so only split the ones known to require a value
+            let (option, value) = arg.split_once('=').unwrap_or((arg, ""));
+            let takes_value = aliases
+                .iter()
+                .any(|(_, long, takes_value)| *takes_value && *long == option);
+            if takes_value && option.len() < arg.len() {
+                normalized.push(option.to_string());
+                normalized.push(value.to_string());
+            } else {
+                normalized.push(arg.clone());
+                if takes_value {
+                    normalized.extend(args.next().cloned());
+                }
+            }
+        } else if let Some(flags) = arg.strip_prefix('-').filter(|flags| !flags.is_empty()) {
+            // only rewrite a bundle we fully understand
+            let Some(first) = flags.chars().next().and_then(lookup) else {
+                normalized.push(arg.clone());
+                continue;
+            };
+            let mut expanded = vec![];
+            let (mut short, mut long, mut takes_value) = *first;
+            let mut rest = &flags[short.len_utf8()..];
+            loop {
+                expanded.push(long.to_string());
+                if takes_value {
+                    match rest {
+                        "" => expanded.extend(args.next().cloned()),
+                        value => expanded.push(value.to_string()),
+                    }
+                    break;
+                }
+                let Some(next) = rest.chars().next() else {
+                    break;
+                };
+                match lookup(next) {
+                    Some(&alias) => {
+                        (short, long, takes_value) = alias;
+                        rest = &rest[short.len_utf8()..];
+                    }
+                    None => {
+                        expanded = vec![arg.clone()];
+                        break;
+                    }
+                }
+            }
+            normalized.extend(expanded);
+        } else {
+            normalized.push(arg.clone());
+        }
+    }
+    normalized
+}
+
+// Like command_is_mutate, but also looks at the arguments of commands that
+// are only sometimes read-only.
+fn invocation_is_mutate(command: GitCommand, args: &[String]) -> bool {
+    match command {
+        GitCommand::Tag => {
+            let tag = GitCommandState::extract_tag_operation(&normalize_args(&command, args));
+            !matches!(tag.action, TagAction::List)
+        }
+        command => command_is_mutate(command),
+    }
+}
+
+/// Reads a long option's value whether it was given as `--option=value` or
+/// as `--option value`, taking the value from `rest` in the second case.
+/// None when `arg` is some other argument or the value is missing.
+fn option_value<'a>(
+    arg: &'a str,
+    option: &str,
+    rest: &mut impl Iterator<Item = &'a String>,
+) -> Option<&'a str> {
+    match arg.strip_prefix(option)? {
+        "" => rest.next().map(String::as_str),
+        inline => inline.strip_prefix('='),
+    }
+}
+
+/// Says why --confirm-destructive should stop a command, if it should.
+fn destructive_reason(git_args: &[String]) -> Option<&'static str> {
+    let git_command = GitCommandState::extract_git_command(&git_args.join(" ")).ok()?;
+    let normalized = normalize_args(&git_command, git_args);
+    let has = |option: &str| normalized.iter().any(|arg| arg == option);
+    let short_flag = |flag: char| {
+        normalized.iter().skip(1).any(|arg| {
+            arg.strip_prefix('-')
+                .is_some_and(|flags| !flags.starts_with('-') && flags.contains(flag))
+        })
+    };
+    match git_command {
+        GitCommand::Push => match GitCommandState::extract_force_push(&normalized)? {
+            ForcePush::Force => Some("force-pushes over the remote"),
+            ForcePush::ForceWithLease => Some("force-pushes with a lease"),
+        },
+        GitCommand::Reset if has("--hard") => Some("discards uncommitted changes"),
+        GitCommand::Clean
+            if (has("--force") || short_flag('f')) && !has("--dry-run") && !short_flag('n') =>
+        {
+            Some("deletes untracked files")
+        }
+        GitCommand::Branch if has("-D") || (has("--delete") && has("--force")) => {
+            Some("deletes a branch even if it isn't merged")
+        }
+        _ => None,
+    }
+}
+
+fn command_is_mutate(command: GitCommand) -> bool {
+    matches!(
+        command,
+        GitCommand::Add
+            | GitCommand::Apply
+            | GitCommand::Bisect
+            | GitCommand::Branch
+            | GitCommand::Checkout
+            | GitCommand::CherryPick
+            | GitCommand::Clean
+            | GitCommand::Clone
+            | GitCommand::Commit
+            | GitCommand::Fetch
+            | GitCommand::FilterBranch
+            | GitCommand::Fsck
+            | GitCommand::Gc
+            | GitCommand::Init
+            | GitCommand::Merge
+            | GitCommand::Mv
+            | GitCommand::Pull
+            | GitCommand::Push
+            | GitCommand::Rebase
+            | GitCommand::Remote
+            | GitCommand::Reset
+            | GitCommand::Restore
+            | GitCommand::Rm
+            | GitCommand::Stash
+            | GitCommand::Submodule
+            | GitCommand::Switch
+            | GitCommand::Tag
+            | GitCommand::UpdateIndex
+            | GitCommand::UpdateRef
+            | GitCommand::WriteTree
+    )
+}
+
+#[derive(Default, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum GitCommand {
+    Add,
+    Apply,
+    Bisect,
+    Branch,
+    Checkout,
+    CherryPick,
+    Clean,
+    Clone,
+    Commit,
+    Fetch,
+    FilterBranch,
+    Fsck,
+    Gc,
+    Init,
+    Merge,
+    Mv,
+    Pull,
+    Push,
+    Rebase,
+    Remote,
+    Reset,
+    Restore,
+    Rm,
+    Stash,
+    Submodule,
+    Switch,
+    Tag,
+    UpdateIndex,
+    UpdateRef,
+    WriteTree,
+    #[default]
+    InvalidCommand,
+    /// A working-tree checkpoint taken with `githist snapshot` rather than
+    /// a git invocation.
+    Snapshot,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct GitCommandState {
+    command: GitCommand,
+    files_affected: Vec<String>,
+    current_branch: String,
+    current_commit: String,
+    /// Parents of HEAD after a commit-creating command, so merges show up
+    /// with two or more entries. Empty for every other command.
+    #[serde(default)]
+    parents: Vec<String>,
+    /// The message of the commit a commit/merge/cherry-pick produced.
+    #[serde(default)]
+    commit_message: Option<String>,
+    /// The repository URL passed to `git submodule add`.
+    #[serde(default)]
+    submodule_url: Option<String>,
+    /// Whether the commit or tag created by a signing `commit -S` or
+    /// `tag -s`/`-u` verified, and the key id that signed it.
+    #[serde(default)]
+    signed: bool,
+    #[serde(default)]
+    signing_key: Option<String>,
+    /// `git status --porcelain=v2` output captured by a snapshot.
+    #[serde(default)]
+    working_tree_status: Option<String>,
+    /// Width of the terminal the command was run from, or `None` when
+    /// stdout wasn't a TTY.
+    #[serde(default)]
+    term_cols: Option<u16>,
+    /// Who a commit was attributed to, as `name <email>`, read back from the
+    /// commit so --author, GIT_AUTHOR_NAME/EMAIL and --amend (which keeps
+    /// the original author) are all reflected.
+    #[serde(default)]
+    author: Option<String>,
+    /// The committer email in effect for commands that create commits. Null
+    /// on such a row means no email was configured.
+    #[serde(default)]
+    committer_email: Option<String>,
+    /// The arguments exactly as passed to git.
+    #[serde(default)]
+    args: Vec<String>,
+    /// `args` with equivalent spellings collapsed, see `normalize_args`.
+    #[serde(default)]
+    normalized_args: Vec<String>,
+    /// The branch checked out before the command ran; `current_branch` is
+    /// the one checked out afterwards, so the two differ for switches.
+    #[serde(default)]
+    branch_before: Option<String>,
+    /// Set when some of `files_affected` lie outside the repository and so
+    /// are stored as absolute paths rather than relative to its root.
+    #[serde(default)]
+    files_outside_repo: bool,
+    /// Set when `files_affected` was cut down to --max-affected-files.
+    #[serde(default)]
+    affected_files_truncated: bool,
+    /// How many files were affected in all, when the list was truncated.
+    #[serde(default)]
+    affected_files_total: Option<usize>,
+    /// Set when git was killed for exceeding `--timeout`.
+    #[serde(default)]
+    timed_out: bool,
+    /// How a push overrode the remote, if it did.
+    #[serde(default)]
+    forced: Option<ForcePush>,
+    /// Links the steps of one bisect run, from `bisect start` to `bisect reset`.
+    #[serde(default)]
+    session_id: Option<String>,
+    /// Top level of the working tree the command ran in, which tells linked
+    /// worktrees of one repository apart.
+    #[serde(default)]
+    worktree: Option<String>,
+    /// The main working tree of the repository, shared by all its linked
+    /// worktrees; also stored in its own column for filtering.
+    #[serde(default)]
+    repo_root: Option<String>,
+    /// Why the command was classified as `invalid_command`.
+    #[serde(default)]
+    invalid_reason: Option<InvalidReason>,
+    /// What a `git tag` invocation did, and to which tags.
+    #[serde(default)]
+    tag: Option<TagOperation>,
+    /// Stash stack size before and after a `git stash` command.
+    #[serde(default)]
+    stash_depth_before: Option<usize>,
+    #[serde(default)]
+    stash_depth_after: Option<usize>,
+    /// The stash a pop, apply, drop, show or branch acted on, as `stash@{n}`.
+    #[serde(default)]
+    stash_ref: Option<String>,
+    /// Whether the working tree had uncommitted changes when the command
+    /// started.
+    #[serde(default)]
+    dirty: Option<bool>,
+    /// How far the branch was ahead of and behind its upstream after a
+    /// fetch, pull or push. Null when the branch has no upstream.
+    #[serde(default)]
+    ahead: Option<usize>,
+    #[serde(default)]
+    behind: Option<usize>,
+    /// How each file changed between the commit checked out before a
+    /// mutating command and the one checked out after it.
+    #[serde(default)]
+    file_changes: Vec<FileChange>,
+    /// Commits a push sent to the upstream: how much less ahead of it the
+    /// branch is afterwards. Null without an upstream on both sides.
+    #[serde(default)]
+    commits_pushed: Option<usize>,
+    /// Commits a pull brought into the branch, not counting merge commits.
+    #[serde(default)]
+    commits_pulled: Option<usize>,
+    /// An interactive rebase's todo plan as it was when the editor closed,
+    /// without comments. Empty if the plan was cleared, which aborts the
+    /// rebase; null if the editor failed.
+    #[serde(default)]
+    rebase_todo: Option<Vec<String>>,
+    /// Set when git printed something that wasn't valid UTF-8 while this row
+    /// was collected, so some stored text has replacement characters.
+    #[serde(default)]
+    lossy_utf8: bool,
+    /// Parallelism asked for with -j/--jobs on clone, fetch, pull or
+    /// submodule; null when not given.
+    #[serde(default)]
+    jobs: Option<u32>,
+    /// The directory git ran in, which differs from the shell's under -C.
+    #[serde(default)]
+    cwd: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct FileChange {
+    status: ChangeType,
+    path: String,
+    /// Where a renamed or copied file came from.
+    old_path: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ChangeType {
+    Added,
+    Modified,
+    Deleted,
+    Renamed,
+    Copied,
+    TypeChanged,
+    Unmerged,
+    Unknown,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TagOperation {
+    action: TagAction,
+    names: Vec<String>,
+    /// Whether a created tag is an annotated tag object rather than a
+    /// lightweight ref.
+    annotated: bool,
+    /// The commit a created tag points at.
+    commit: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum TagAction {
+    Create,
+    Delete,
+    /// Listing or verifying tags, which changes nothing.
+    List,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum InvalidReason {
+    /// No subcommand was given at all.
+    Empty,
+    /// The subcommand is a git alias, which isn't expanded before classifying.
+    AliasUnresolved,
+    /// Anything else: a typo, or a git command the enum doesn't know yet.
+    UnknownToken,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ForcePush {
+    /// `--force`, `-f` or a `+refspec`: overwrites whatever the remote has.
+    Force,
+    /// `--force-with-lease`: only overwrites what we last fetched.
+    ForceWithLease,
+}
+
+/// Repository state that has to be read before git runs the command,
+/// because the command may change it.
+struct PreCommandState {
+    branch: String,
+    commit: String,
+    stash_depth: Option<usize>,
+    dirty: Option<bool>,
+    /// (ahead, behind) of the upstream, only captured for pushes.
+    divergence: Option<(usize, usize)>,
+}
+
+impl PreCommandState {
+    fn capture(command: &str) -> std::io::Result<PreCommandState> {
+        let git_command = GitCommandState::extract_git_command(command);
+        let is_stash = matches!(git_command, Ok(GitCommand::Stash));
+        let is_push = matches!(git_command, Ok(GitCommand::Push));
+        let (branch, commit) = get_current_branch_and_commit()?;
+        let before = PreCommandState {
+            branch,
+            commit,
+            stash_depth: is_stash.then(get_stash_depth).flatten(),
+            dirty: get_is_dirty(),
+            divergence: is_push.then(get_upstream_divergence).flatten(),
+        };
+        log::debug!("branch before command: {}", before.branch.trim_end());
+        Ok(before)
+    }
+}
+
+// Resolves the current branch and commit with a single rev-parse. Both keep
+// their trailing newline, as they always have been stored that way.
+//
+// This is the first git call for a recorded command, so failing to run git
+// at all is returned rather than panicking.
+fn get_current_branch_and_commit() -> std::io::Result<(String, String)> {
+    let output = git()
+        .args(["rev-parse", "HEAD", "--abbrev-ref", "HEAD"])
+        .output()?;
+    let stdout = decode_git_output(output.stdout);
+    if !output.status.success() {
+        log::warn!(
+            "could not resolve HEAD: {}",
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        );
+        // rev-parse stops at the first revision it can't resolve, e.g. on
+        // an unborn branch, echoing it for both
+        return Ok((stdout.clone(), stdout));
+    }
+    let mut lines = stdout.lines().map(|line| format!("{}\n", line));
+    let commit = lines.next().unwrap_or_default();
+    let branch = lines.next().unwrap_or_default();
+    Ok((branch, commit))
+}
+
+fn get_head_parents() -> Vec<String> {
+    let output = git()
+        .args(["rev-parse", "HEAD^@"])
+        .output()
+        .expect("failed to execute process");
+    if !output.status.success() {
+        return vec![];
+    }
+    decode_git_output(output.stdout)
+        .lines()
+        .map(|line| line.to_string())
+        .collect()
+}
+
+fn get_stash_depth() -> Option<usize> {
+    let output = git()
+        .args(["stash", "list"])
+        .output()
+        .expect("failed to execute process");
+    if !output.status.success() {
+        return None;
+    }
+    Some(decode_git_output(output.stdout).lines().count())
+}
+
+// Parses `git diff --name-status -z` between two commits. Rename and copy
+// entries carry a similarity score after their letter and two paths.
+fn get_file_changes(from: &str, to: &str) -> Vec<FileChange> {
+    let output = git()
+        .args([
+            "-c",
+            "core.quotepath=false",
+            "diff",
+            "--name-status",
+            "-z",
+            "-M",
+            from,
+            to,
+        ])
+        .output()
+        .expect("failed to execute process");
+    if !output.status.success() {
+        return vec![];
+    }
+    let stdout = decode_git_output(output.stdout);
+    let mut fields = stdout.split('\0').filter(|field| !field.is_empty());
+    let mut changes = vec![];
+    while let (Some(status), Some(path)) = (fields.next(), fields.next()) {
+        let status = match status.chars().next() {
+            Some('A') => ChangeType::Added,
+            Some('M') => ChangeType::Modified,
+            Some('D') => ChangeType::Deleted,
+            Some('R') => ChangeType::Renamed,
+            Some('C') => ChangeType::Copied,
+            Some('T') => ChangeType::TypeChanged,
+            Some('U') => ChangeType::Unmerged,
+            _ => ChangeType::Unknown,
+        };
+        let change = match status {
+            ChangeType::Renamed | ChangeType::Copied => FileChange {
+                status,
+                path: fields.next().unwrap_or_default().to_string(),
+                old_path: Some(path.to_string()),
+            },
+            status => FileChange {
+                status,
+                path: path.to_string(),
+                old_path: None,
+            },
+        };
+        changes.push(change);
+    }
+    changes
+}
+
+// Returns (ahead, behind) relative to the current branch's upstream.
+fn get_upstream_divergence() -> Option<(usize, usize)> {
+    let output = git()
+        .args(["rev-list", "--left-right", "--count", "@{upstream}...HEAD"])
+        .output()
+        .expect("failed to execute process");
+    if !output.status.success() {
+        return None;
+    }
+    let counts = decode_git_output(output.stdout);
+    // the left side counts upstream-only commits, the right side ours
+    let (behind, ahead) = counts.trim_end().split_once('\t')?;
+    Some((ahead.parse().ok()?, behind.parse().ok()?))
+}
+
+fn count_commits(from: &str, to: &str) -> Option<usize> {
+    let output = git()
+        .args(["rev-list", "--count", "--no-merges"])
+        .arg(format!("{}..{}", from, to))
+        .output()
+        .expect("failed to execute process");
+    if !output.status.success() {
+        return None;
+    }
+    decode_git_output(output.stdout).trim_end().parse().ok()
+}
+
+// Oldest first: the names end in a unix timestamp, so they sort by age.
+fn list_backup_branches() -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let output = git()
+        .args([
+            "for-each-ref",
+            "--sort=refname",
+            "--format=%(refname:short)",
+        ])
+        .arg(format!("refs/heads/{}*", BACKUP_BRANCH_PREFIX))
+        .output()
+        .map_err(GithistError::GitFailed)?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim_end().into());
+    }
+    Ok(decode_git_output(output.stdout)
+        .lines()
+        .map(str::to_string)
+        .collect())
+}
+
+fn get_tagged_commit(tag: &str) -> Option<String> {
+    let output = git()
+        .args(["rev-parse", "--verify", "--quiet"])
+        .arg(format!("refs/tags/{}^{{commit}}", tag))
+        .output()
+        .expect("failed to execute process");
+    if !output.status.success() {
+        return None;
+    }
+    Some(decode_git_output(output.stdout).trim_end().to_string())
+}
+
+fn get_head_commit_message() -> Option<String> {
+    let output = git()
+        .args(["log", "-1", "--format=%B"])
+        .output()
+        .expect("failed to execute process");
+    if !output.status.success() {
+        return None;
+    }
+    Some(decode_git_output(output.stdout).trim_end().to_string())
+}
+
+fn get_head_author() -> Option<String> {
+    let output = git()
+        .args(["log", "-1", "--format=%an <%ae>"])
+        .output()
+        .expect("failed to execute process");
+    if !output.status.success() {
+        return None;
+    }
+    Some(decode_git_output(output.stdout).trim_end().to_string())
+}
+
+// Returns whether the object verified and, for GPG signatures, the key id
+// from the GOODSIG status line. Verification fails (and we record unsigned)
+// when gpg isn't set up or the object carries no signature.
+fn verify_signature(kind: &str, object: &str) -> (bool, Option<String>) {
+    let output = git()
+        .args([kind, "--raw", object])
+        .output()
+        .expect("failed to execute process");
+    if !output.status.success() {
+        return (false, None);
+    }
+    let key = String::from_utf8_lossy(&output.stderr)
+        .lines()
+        .find_map(|line| line.strip_prefix("[GNUPG:] GOODSIG "))
+        .and_then(|line| line.split(' ').next())
+        .map(|key| key.to_string());
+    (true, key)
+}
+
+// Whether `git status --porcelain` reports anything, untracked files
+// included; None outside a repository. The history database and its mirror
+// usually sit untracked in the working tree, so they don't count.
+fn get_is_dirty() -> Option<bool> {
+    let output = git()
+        .args(["status", "--porcelain", "--", ":/"])
+        .arg(format!(
+            ":(top,exclude,glob)**/{}*",
+            GIT_COMMAND_HISTORY_FILE_PATH
+        ))
+        .output()
+        .expect("failed to execute process");
+    if !output.status.success() {
+        return None;
+    }
+    Some(!output.stdout.is_empty())
+}
+
+// The paths an entry of `git status --porcelain=v2` names: a changed entry
+// has its path after eight fields, a rename or copy has two tab-separated
+// paths after nine, an unmerged entry has its path after ten, and untracked
+// and ignored entries have just the path.
+fn porcelain_v2_paths(line: &str) -> Vec<&str> {
+    match line.split_once(' ') {
+        Some(("1", entry)) => entry.splitn(8, ' ').skip(7).collect(),
+        Some(("2", entry)) => entry
+            .splitn(9, ' ')
+            .skip(8)
+            .flat_map(|paths| paths.split('\t'))
+            .collect(),
+        Some(("u", entry)) => entry.splitn(10, ' ').skip(9).collect(),
+        Some(("?" | "!", path)) => vec![path],
+        _ => vec![],
+    }
+}
+
+fn get_working_tree_status() -> String {
+    let output = git()
+        .args(["-c", "core.quotepath=false", "status", "--porcelain=v2"])
+        .output()
+        .expect("failed to execute process");
+    decode_git_output(output.stdout)
+}
+
+// None when there's no repository to ask, e.g. when reading a copied
+// history database elsewhere, possibly without git installed.
+fn branch_exists(branch: &str) -> Option<bool> {
+    let status = git()
+        .args(["rev-parse", "--verify", "--quiet"])
+        .arg(format!("refs/heads/{}", branch))
+        .output()
+        .ok()?
+        .status;
+    // --verify --quiet exits 1 for a missing ref, 128 outside a repository
+    match status.code() {
+        Some(0) => Some(true),
+        Some(1) => Some(false),
+        _ => None,
     }
-    Ok(())
 }
 
-fn add_command_history(
-    conn: &rusqlite::Connection,
-    command: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let command = GitCommandState::new(command);
-    conn.execute(
-        "INSERT INTO git_command_history (id, command, created_at) VALUES (?1, ?2, ?3)",
-        [
-            Uuid::new_v4().to_string(),
-            serde_json::to_string(&command)?,
-            time::OffsetDateTime::now_utc().to_string(),
-        ],
-    )?;
-    Ok(())
-}
+static GIT_DIRECTORY: std::sync::OnceLock<std::path::PathBuf> = std::sync::OnceLock::new();
 
-fn command_is_mutate(command: GitCommand) -> bool {
-    match command {
-        GitCommand::Add => true,
-        GitCommand::Apply => true,
-        GitCommand::Bisect => true,
-        GitCommand::Branch => true,
-        GitCommand::Checkout => true,
-        GitCommand::CherryPick => true,
-        GitCommand::Clean => true,
-        GitCommand::Clone => true,
-        GitCommand::Commit => true,
-        GitCommand::Fetch => true,
-        GitCommand::FilterBranch => true,
-        GitCommand::Fsck => true,
-        GitCommand::Gc => true,
-        GitCommand::Init => true,
-        GitCommand::Merge => true,
-        GitCommand::Mv => true,
-        GitCommand::Pull => true,
-        GitCommand::Push => true,
-        GitCommand::Rebase => true,
-        GitCommand::Remote => true,
-        GitCommand::Reset => true,
-        GitCommand::Restore => true,
-        GitCommand::Rm => true,
-        GitCommand::Stash => true,
-        GitCommand::Submodule => true,
-        GitCommand::Switch => true,
-        GitCommand::Tag => true,
-        GitCommand::UpdateIndex => true,
-        GitCommand::UpdateRef => true,
-        GitCommand::WriteTree => true,
-        _ => false,
+// Every git invocation goes through here so that -C applies to all of them.
+fn git() -> std::process::Command {
+    let mut command = std::process::Command::new("git");
+    if let Some(dir) = GIT_DIRECTORY.get() {
+        command.current_dir(dir);
     }
+    command
 }
 
-#[derive(Serialize, Deserialize)]
-#[serde(rename_all = "snake_case")]
-enum GitCommand {
-    Add,
-    Apply,
-    Bisect,
-    Branch,
-    Checkout,
-    CherryPick,
-    Clean,
-    Clone,
-    Commit,
-    Fetch,
-    FilterBranch,
-    Fsck,
-    Gc,
-    Init,
-    Merge,
-    Mv,
-    Pull,
-    Push,
-    Rebase,
-    Remote,
-    Reset,
-    Restore,
-    Rm,
-    Stash,
-    Submodule,
-    Switch,
-    Tag,
-    UpdateIndex,
-    UpdateRef,
-    WriteTree,
-    InvalidCommand,
+// The directory git runs in, which relative paths in its arguments are
+// resolved against.
+fn command_dir() -> std::io::Result<std::path::PathBuf> {
+    match GIT_DIRECTORY.get() {
+        Some(dir) => Ok(dir.clone()),
+        None => std::env::current_dir(),
+    }
 }
 
-#[derive(Serialize, Deserialize)]
-struct GitCommandState {
-    command: GitCommand,
-    files_affected: Vec<String>,
-    current_branch: String,
-    current_commit: String,
+static LOSSY_GIT_OUTPUT: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+// git passes filenames, messages and ref names through as raw bytes, so
+// invalid UTF-8 is replaced rather than fatal. Replacements are remembered so
+// the row can be flagged as not quite what git printed.
+fn decode_git_output(bytes: Vec<u8>) -> String {
+    String::from_utf8(bytes).unwrap_or_else(|err| {
+        log::warn!("git printed invalid UTF-8, replacing it");
+        LOSSY_GIT_OUTPUT.store(true, std::sync::atomic::Ordering::Relaxed);
+        String::from_utf8_lossy(err.as_bytes()).into_owned()
+    })
 }
 
-fn get_current_commit() -> String {
-    let output = std::process::Command::new("git")
-        .args(&["rev-parse", "HEAD"])
+fn get_git_editor() -> Option<String> {
+    let output = git()
+        .args(["var", "GIT_EDITOR"])
         .output()
         .expect("failed to execute process");
-    String::from_utf8(output.stdout).unwrap()
+    if !output.status.success() {
+        return None;
+    }
+    Some(decode_git_output(output.stdout).trim_end().to_string())
 }
 
-fn get_current_branch() -> String {
-    let output = std::process::Command::new("git")
-        .args(&["rev-parse", "--abbrev-ref", "HEAD"])
+fn get_git_config(key: &str) -> Option<String> {
+    let output = git()
+        .args(["config", "--get", key])
         .output()
         .expect("failed to execute process");
-    String::from_utf8(output.stdout).unwrap()
+    if !output.status.success() {
+        return None;
+    }
+    Some(decode_git_output(output.stdout).trim_end().to_string())
+}
+
+// The current worktree's root and the repository's (see
+// get_repo_common_root), from one rev-parse. That fails outside a working
+// tree, e.g. in a bare repository, which is then looked up on its own.
+fn get_repo_roots() -> (Option<std::path::PathBuf>, Option<std::path::PathBuf>) {
+    let output = git()
+        .args([
+            "rev-parse",
+            "--show-toplevel",
+            "--path-format=absolute",
+            "--git-common-dir",
+        ])
+        .output();
+    match output {
+        Ok(output) if output.status.success() => {
+            let output = decode_git_output(output.stdout);
+            let mut lines = output.lines();
+            let worktree = lines.next().map(std::path::PathBuf::from);
+            let common_root = lines.next().map(|dir| common_dir_root(dir.into()));
+            (worktree, common_root)
+        }
+        _ => (None, get_repo_common_root()),
+    }
+}
+
+// The repository's main working tree, found from the git directory that
+// every worktree shares; a bare repository is identified by that directory.
+// Read-only subcommands use this to filter history, so they must work on a
+// copied database without git installed: failing to run git means no
+// repository.
+fn get_repo_common_root() -> Option<std::path::PathBuf> {
+    let output = git()
+        .args(["rev-parse", "--path-format=absolute", "--git-common-dir"])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(common_dir_root(
+        decode_git_output(output.stdout).trim_end().into(),
+    ))
+}
+
+fn common_dir_root(common_dir: std::path::PathBuf) -> std::path::PathBuf {
+    match (common_dir.file_name(), common_dir.parent()) {
+        (Some(name), Some(root)) if name == ".git" => root.to_path_buf(),
+        _ => common_dir,
+    }
+}
+
+// Resolves `.` and `..` without touching the filesystem, so symlinks in the
+// path are kept as given.
+fn normalize_path(path: &std::path::Path) -> std::path::PathBuf {
+    let mut normalized = std::path::PathBuf::new();
+    for component in path.components() {
+        match component {
+            std::path::Component::CurDir => {}
+            std::path::Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
 }
 
 impl GitCommandState {
     fn extract_git_command(command: &str) -> Result<GitCommand, Box<dyn std::error::Error>> {
-        return match command.split(" ").next().unwrap_or_else(|| "") {
+        match command.split(" ").next().unwrap_or("") {
             "add" => Ok(GitCommand::Add),
             "apply" => Ok(GitCommand::Apply),
             "bisect" => Ok(GitCommand::Bisect),
@@ -215,29 +2919,1014 @@ impl GitCommandState {
             "update-ref" => Ok(GitCommand::UpdateRef),
             "write-tree" => Ok(GitCommand::WriteTree),
             _ => Err("No command found".into()),
-        };
+        }
     }
 
     // This is really quite a naive implementation, but it should work for now.
-    fn process_affected_files(command: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    fn process_affected_files(
+        command: &str,
+        canonicalize: bool,
+    ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
         let mut files_affected = vec![];
+        let dir = command_dir()?;
         for string in command.split(" ") {
-            if std::path::Path::new(string).exists() {
-                files_affected.push(string.to_string());
+            if dir.join(string).exists() {
+                // fall back to the literal path rather than losing the entry
+                let file = canonicalize
+                    .then(|| std::fs::canonicalize(dir.join(string)).ok())
+                    .flatten()
+                    .map_or_else(|| string.to_string(), |path| path.display().to_string());
+                files_affected.push(file);
             }
         }
         Ok(files_affected)
     }
 
-    fn new(command: &str) -> GitCommandState {
-        let git_command = { GitCommandState::extract_git_command(command) }
-            .unwrap_or_else(|_| GitCommand::InvalidCommand);
+    fn classify_invalid(command: &str) -> InvalidReason {
+        match command.split(" ").next().unwrap_or("") {
+            "" => InvalidReason::Empty,
+            token if get_git_config(&format!("alias.{}", token)).is_some() => {
+                InvalidReason::AliasUnresolved
+            }
+            _ => InvalidReason::UnknownToken,
+        }
+    }
+
+    // Messages given with -m/--message (repeatable, joined into paragraphs the
+    // way git does) or -F/--file. Anything else, like the editor or -C, is
+    // picked up from the resulting commit instead.
+    fn extract_commit_message(args: &[String]) -> Option<String> {
+        let mut messages = vec![];
+        let mut args = args.iter().skip(1);
+        while let Some(arg) = args.next() {
+            if let Some(message) = option_value(arg, "--message", &mut args) {
+                messages.push(message.to_string());
+            } else if let Some(path) = option_value(arg, "--file", &mut args) {
+                return std::fs::read_to_string(command_dir().ok()?.join(path)).ok();
+            } else if arg == "-F" {
+                let path = args.next()?;
+                return std::fs::read_to_string(command_dir().ok()?.join(path)).ok();
+            } else if let Some((flags, message)) = arg
+                .strip_prefix('-')
+                .filter(|arg| !arg.starts_with('-'))
+                .and_then(|arg| arg.split_once('m'))
+            {
+                // bundled boolean flags such as `-am "message"`
+                if !flags.chars().all(|flag| "anqsv".contains(flag)) {
+                    continue;
+                }
+                if message.is_empty() {
+                    messages.extend(args.next().cloned());
+                } else {
+                    messages.push(message.to_string());
+                }
+            }
+        }
+        if messages.is_empty() {
+            None
+        } else {
+            Some(messages.join("\n\n"))
+        }
+    }
+
+    // Submodule paths often don't exist yet (`add`, `update --init`), so they
+    // are taken from the arguments rather than found by checking the disk.
+    // Returns the submodule paths and, for `add`, the repository URL.
+    fn extract_submodule_targets(args: &[String]) -> (Vec<String>, Option<String>) {
+        const OPTIONS_WITH_VALUES: &[&str] = &[
+            "-b",
+            "--branch",
+            "--name",
+            "--reference",
+            "--depth",
+            "-j",
+            "--jobs",
+            "--filter",
+            "-n",
+            "--summary-limit",
+        ];
+        let mut subcommand = None;
+        let mut positionals = vec![];
+        let mut args = args.iter().skip(1);
+        let mut options_done = false;
+        while let Some(arg) = args.next() {
+            if !options_done && arg == "--" {
+                options_done = true;
+            } else if !options_done && OPTIONS_WITH_VALUES.contains(&arg.as_str()) {
+                args.next();
+            } else if !options_done && arg.starts_with('-') {
+                continue;
+            } else if subcommand.is_none() {
+                subcommand = Some(arg.as_str());
+            } else {
+                positionals.push(arg.clone());
+            }
+        }
+        match subcommand {
+            Some("add") => {
+                let mut positionals = positionals.into_iter();
+                let url = positionals.next();
+                let path = positionals.next().or_else(|| {
+                    url.as_deref().and_then(|url| {
+                        let name = url.trim_end_matches('/').rsplit(['/', ':']).next()?;
+                        Some(name.trim_end_matches(".git").to_string())
+                    })
+                });
+                (path.into_iter().collect(), url)
+            }
+            // foreach takes a shell command and summary takes a commit
+            Some("foreach") | Some("summary") | None => (vec![], None),
+            Some(_) => (positionals, None),
+        }
+    }
+
+    // Works on normalized arguments so bundles like `-fu` are already split.
+    // A plain force anywhere wins over a lease since it disables the check.
+    fn extract_force_push(normalized_args: &[String]) -> Option<ForcePush> {
+        let mut force = false;
+        let mut lease = false;
+        let mut options_done = false;
+        for arg in normalized_args.iter().skip(1) {
+            match arg.as_str() {
+                "--" => options_done = true,
+                "--force" if !options_done => force = true,
+                "--no-force" if !options_done => force = false,
+                lease_arg
+                    if !options_done
+                        && (lease_arg == "--force-with-lease"
+                            || lease_arg.starts_with("--force-with-lease=")) =>
+                {
+                    lease = true
+                }
+                "--no-force-with-lease" if !options_done => lease = false,
+                refspec if refspec.starts_with('+') => force = true,
+                _ => {}
+            }
+        }
+        match (force, lease) {
+            (true, _) => Some(ForcePush::Force),
+            (false, true) => Some(ForcePush::ForceWithLease),
+            (false, false) => None,
+        }
+    }
+
+    fn commit_is_signed(args: &[String]) -> bool {
+        let mut signed = false;
+        for arg in args.iter().skip(1) {
+            if arg.starts_with("-S") || arg == "--gpg-sign" || arg.starts_with("--gpg-sign=") {
+                signed = true;
+            } else if arg == "--no-gpg-sign" {
+                signed = false;
+            }
+        }
+        signed
+    }
+
+    // Returns the tag name when the `git tag` invocation creates a signed tag.
+    fn extract_signed_tag(args: &[String]) -> Option<String> {
+        const OPTIONS_WITH_VALUES: &[&str] = &["-m", "--message", "-F", "--file", "--cleanup"];
+        let mut signed = false;
+        let mut name = None;
+        let mut args = args.iter().skip(1);
+        while let Some(arg) = args.next() {
+            if arg == "-s" || arg == "--sign" {
+                signed = true;
+            } else if arg == "-u" {
+                signed = true;
+                args.next();
+            } else if option_value(arg, "--local-user", &mut args).is_some() {
+                signed = true;
+            } else if OPTIONS_WITH_VALUES.contains(&arg.as_str()) {
+                args.next();
+            } else if !arg.starts_with('-') && name.is_none() {
+                name = Some(arg.clone());
+            }
+        }
+        name.filter(|_| signed)
+    }
+
+    // The last -j/--jobs wins, as in git. Pull and submodule have no flag
+    // alias for -j, so its unnormalized spellings are handled here too.
+    fn extract_jobs(normalized_args: &[String]) -> Option<u32> {
+        let mut jobs = None;
+        let mut args = normalized_args.iter().skip(1);
+        while let Some(arg) = args.next() {
+            if arg == "--" {
+                break;
+            }
+            let value = option_value(arg, "--jobs", &mut args)
+                .or_else(|| option_value(arg, "-j", &mut args))
+                .or_else(|| arg.strip_prefix("-j"));
+            if let Some(value) = value {
+                jobs = value.parse().ok();
+            }
+        }
+        jobs
+    }
+
+    // Finds the stash an invocation names, spelled `stash@{n}` or just `n`.
+    // Commands that take a stash default to the top one.
+    fn extract_stash_ref(args: &[String]) -> Option<String> {
+        let subcommand = args.get(1)?;
+        let names_stash = match subcommand.as_str() {
+            "pop" | "apply" | "drop" | "show" => 2,
+            "branch" => 3,
+            _ => return None,
+        };
+        let positionals: Vec<&String> = args
+            .iter()
+            .skip(2)
+            .filter(|arg| !arg.starts_with('-'))
+            .collect();
+        Some(match positionals.get(names_stash - 2) {
+            Some(arg) if arg.parse::<usize>().is_ok() => format!("stash@{{{}}}", arg),
+            Some(arg) => arg.to_string(),
+            None => "stash@{0}".to_string(),
+        })
+    }
+
+    // Classifies a `git tag` invocation from its normalized arguments. The
+    // tagged commit is left for the caller to resolve once git has run.
+    fn extract_tag_operation(normalized_args: &[String]) -> TagOperation {
+        const OPTIONS_WITH_VALUES: &[&str] = &["--message", "--file", "--local-user", "--cleanup"];
+        const LIST_OPTIONS: &[&str] = &[
+            "--list",
+            "--verify",
+            "--contains",
+            "--no-contains",
+            "--points-at",
+            "--merged",
+            "--no-merged",
+            "--sort",
+            "--format",
+        ];
+        let mut delete = false;
+        let mut list = false;
+        let mut annotated = false;
+        let mut names = vec![];
+        let mut args = normalized_args.iter().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--delete" => delete = true,
+                "--annotate" | "--sign" => annotated = true,
+                option if OPTIONS_WITH_VALUES.contains(&option) => {
+                    // a message or signing key makes git create an annotated tag
+                    annotated |= option != "--cleanup";
+                    args.next();
+                }
+                option
+                    if LIST_OPTIONS.contains(&option.split('=').next().unwrap_or(option))
+                        || option.starts_with("-n") =>
+                {
+                    list = true
+                }
+                option if option.starts_with('-') => {}
+                name => names.push(name.to_string()),
+            }
+        }
+        let action = if delete {
+            TagAction::Delete
+        } else if list || names.is_empty() {
+            TagAction::List
+        } else {
+            // the second positional is the object being tagged, not a tag
+            names.truncate(1);
+            TagAction::Create
+        };
+        TagOperation {
+            annotated: annotated && matches!(action, TagAction::Create),
+            action,
+            names,
+            commit: None,
+        }
+    }
+
+    // Rewrites paths relative to the repository root so the same file is
+    // recorded the same way from any directory. Files outside the repository
+    // are kept as absolute paths and reported through the returned flag.
+    fn relative_to_repo(files: Vec<String>, repo_root: &std::path::Path) -> (Vec<String>, bool) {
+        let Ok(cwd) = command_dir() else {
+            return (files, false);
+        };
+        let mut outside_repo = false;
+        let mut relative: Vec<String> = vec![];
+        for file in files {
+            let path = normalize_path(&cwd.join(&file));
+            let file = match path.strip_prefix(repo_root) {
+                Ok(path) if path.as_os_str().is_empty() => ".".to_string(),
+                Ok(path) => path.display().to_string(),
+                Err(_) => {
+                    outside_repo = true;
+                    path.display().to_string()
+                }
+            };
+            if !relative.contains(&file) {
+                relative.push(file);
+            }
+        }
+        (relative, outside_repo)
+    }
+
+    // Removes every mention of `path` from the fields that store paths,
+    // returning whether there was one. Arguments are replaced by
+    // SCRUBBED_ARG rather than dropped, so the rest keep their meaning:
+    // `commit -m <path>` mustn't become a bare `commit -m`.
+    fn scrub_path(&mut self, path: &str) -> bool {
+        let len = self.files_affected.len();
+        self.files_affected.retain(|file| file != path);
+        let mut scrubbed = self.files_affected.len() != len;
+        for arg in self.args.iter_mut().chain(&mut self.normalized_args) {
+            if arg == path {
+                *arg = SCRUBBED_ARG.to_string();
+                scrubbed = true;
+            }
+        }
+        let len = self.file_changes.len();
+        self.file_changes
+            .retain(|change| change.path != path && change.old_path.as_deref() != Some(path));
+        scrubbed |= self.file_changes.len() != len;
+        if let Some(status) = &mut self.working_tree_status {
+            let kept: Vec<&str> = status
+                .lines()
+                .filter(|line| !porcelain_v2_paths(line).contains(&path))
+                .collect();
+            if kept.len() != status.lines().count() {
+                *status = kept.iter().map(|line| format!("{}\n", line)).collect();
+                scrubbed = true;
+            }
+        }
+        scrubbed
+    }
+
+    fn truncate_affected_files(&mut self, max: usize) {
+        if self.files_affected.len() <= max {
+            return;
+        }
+        log::debug!(
+            "keeping {} of {} affected files",
+            max,
+            self.files_affected.len()
+        );
+        self.affected_files_total = Some(self.files_affected.len());
+        self.affected_files_truncated = true;
+        self.files_affected.truncate(max);
+    }
+
+    fn snapshot() -> std::io::Result<GitCommandState> {
+        let (current_branch, current_commit) = get_current_branch_and_commit()?;
+        let (worktree, repo_root) = get_repo_roots();
+        Ok(GitCommandState {
+            command: GitCommand::Snapshot,
+            current_branch,
+            current_commit,
+            working_tree_status: Some(get_working_tree_status()),
+            worktree: worktree.map(|root| root.display().to_string()),
+            repo_root: repo_root.map(|root| root.display().to_string()),
+            lossy_utf8: LOSSY_GIT_OUTPUT.load(std::sync::atomic::Ordering::Relaxed),
+            ..Default::default()
+        })
+    }
+
+    fn new(
+        args: &[String],
+        canonicalize: bool,
+        before: PreCommandState,
+        status: std::process::ExitStatus,
+    ) -> GitCommandState {
+        let command = &args.join(" ");
+        let git_command =
+            GitCommandState::extract_git_command(command).unwrap_or(GitCommand::InvalidCommand);
+        // what a command produced (a commit, a tag) is only read back when it
+        // succeeded; otherwise HEAD and the tags are still what they were
+        let succeeded = status.success();
+        let parents = if succeeded && command_creates_commit(&git_command) {
+            get_head_parents()
+        } else {
+            vec![]
+        };
+        let commit_message = match git_command {
+            GitCommand::Commit | GitCommand::Merge if succeeded => {
+                GitCommandState::extract_commit_message(args)
+                    .map(|message| message.trim_end().to_string())
+                    .or_else(get_head_commit_message)
+            }
+            GitCommand::CherryPick if succeeded => get_head_commit_message(),
+            _ => None,
+        };
+        let mut files_affected = GitCommandState::process_affected_files(command, canonicalize)
+            .unwrap_or_else(|_| vec![]);
+        let mut submodule_url = None;
+        if let GitCommand::Submodule = git_command {
+            let (paths, url) = GitCommandState::extract_submodule_targets(args);
+            for path in paths {
+                if !files_affected.contains(&path) {
+                    files_affected.push(path);
+                }
+            }
+            submodule_url = url;
+        }
+        let mut files_outside_repo = false;
+        let (worktree, repo_root) = get_repo_roots();
+        if let Some(worktree) = &worktree {
+            (files_affected, files_outside_repo) =
+                GitCommandState::relative_to_repo(files_affected, worktree);
+        }
+        let (signed, signing_key) = match git_command {
+            GitCommand::Commit if succeeded && GitCommandState::commit_is_signed(args) => {
+                verify_signature("verify-commit", "HEAD")
+            }
+            GitCommand::Tag if succeeded => GitCommandState::extract_signed_tag(args)
+                .map_or((false, None), |tag| verify_signature("verify-tag", &tag)),
+            _ => (false, None),
+        };
+        let normalized_args = normalize_args(&git_command, args);
+        let jobs = match git_command {
+            GitCommand::Clone | GitCommand::Fetch | GitCommand::Pull | GitCommand::Submodule => {
+                GitCommandState::extract_jobs(&normalized_args)
+            }
+            _ => None,
+        };
+        let forced = match git_command {
+            GitCommand::Push => GitCommandState::extract_force_push(&normalized_args),
+            _ => None,
+        };
+        let author = match git_command {
+            GitCommand::Commit if succeeded => get_head_author(),
+            _ => None,
+        };
+        let committer_email = if succeeded && command_creates_commit(&git_command) {
+            let email = std::env::var("GIT_COMMITTER_EMAIL")
+                .ok()
+                .or_else(|| get_git_config("user.email"));
+            if email.is_none() {
+                log::warn!("no committer email is configured");
+            }
+            email
+        } else {
+            None
+        };
+        let tag = match git_command {
+            GitCommand::Tag => {
+                let mut tag = GitCommandState::extract_tag_operation(&normalized_args);
+                if let (true, TagAction::Create, Some(name)) =
+                    (succeeded, &tag.action, tag.names.first())
+                {
+                    tag.commit = get_tagged_commit(name);
+                }
+                Some(tag)
+            }
+            _ => None,
+        };
+        let (stash_depth_after, stash_ref) = match git_command {
+            GitCommand::Stash => (get_stash_depth(), GitCommandState::extract_stash_ref(args)),
+            _ => (None, None),
+        };
+        let (ahead, behind) = match git_command {
+            GitCommand::Fetch | GitCommand::Pull | GitCommand::Push => {
+                get_upstream_divergence().unzip()
+            }
+            _ => (None, None),
+        };
+        let commits_pushed = match (&git_command, before.divergence, ahead) {
+            (GitCommand::Push, Some((ahead_before, _)), Some(ahead_after)) => {
+                ahead_before.checked_sub(ahead_after)
+            }
+            _ => None,
+        };
+        let invalid_reason = match git_command {
+            GitCommand::InvalidCommand => Some(GitCommandState::classify_invalid(command)),
+            _ => None,
+        };
+        let (current_branch, current_commit) =
+            get_current_branch_and_commit().expect("failed to execute process");
+        let commits_pulled = match git_command {
+            GitCommand::Pull => count_commits(before.commit.trim_end(), current_commit.trim_end()),
+            _ => None,
+        };
+        let file_changes = if command_is_mutate(git_command) && before.commit != current_commit {
+            get_file_changes(before.commit.trim_end(), current_commit.trim_end())
+        } else {
+            vec![]
+        };
         GitCommandState {
             command: git_command,
-            files_affected: GitCommandState::process_affected_files(command)
-                .unwrap_or_else(|_| vec![]),
-            current_branch: get_current_branch(),
-            current_commit: get_current_commit(),
+            files_affected,
+            current_branch,
+            current_commit,
+            parents,
+            commit_message,
+            submodule_url,
+            signed,
+            signing_key,
+            working_tree_status: None,
+            term_cols: get_terminal_width(),
+            author,
+            committer_email,
+            normalized_args,
+            args: args.to_vec(),
+            branch_before: Some(before.branch),
+            files_outside_repo,
+            affected_files_truncated: false,
+            affected_files_total: None,
+            timed_out: false,
+            forced,
+            session_id: None,
+            invalid_reason,
+            tag,
+            stash_depth_before: before.stash_depth,
+            stash_depth_after,
+            stash_ref,
+            dirty: before.dirty,
+            ahead,
+            behind,
+            file_changes,
+            commits_pushed,
+            commits_pulled,
+            rebase_todo: None,
+            jobs,
+            worktree: worktree.map(|root| root.display().to_string()),
+            repo_root: repo_root.map(|root| root.display().to_string()),
+            cwd: command_dir().ok().map(|dir| dir.display().to_string()),
+            // last, so every git call above has been decoded already
+            lossy_utf8: LOSSY_GIT_OUTPUT.load(std::sync::atomic::Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn normalized(command: GitCommand, raw: &[&str]) -> Vec<String> {
+        normalize_args(&command, &args(raw))
+    }
+
+    #[test]
+    fn normalize_args_groups_spellings_of_one_commit() {
+        let expected = args(&["commit", "--all", "--message", "x"]);
+        for spelling in [
+            &["commit", "-am", "x"][..],
+            &["commit", "-amx"],
+            &["commit", "-a", "-m", "x"],
+            &["commit", "--all", "--message=x"],
+            &["commit", "--all", "--message", "x"],
+        ] {
+            assert_eq!(
+                normalized(GitCommand::Commit, spelling),
+                expected,
+                "{:?}",
+                spelling
+            );
+        }
+    }
+
+    #[test]
+    fn normalize_args_only_splits_options_that_require_a_value() {
+        assert_eq!(
+            normalized(GitCommand::Switch, &["switch", "--create=topic"]),
+            args(&["switch", "--create", "topic"])
+        );
+        assert_eq!(
+            normalized(GitCommand::Commit, &["commit", "--untracked-files=no"]),
+            args(&["commit", "--untracked-files=no"])
+        );
+        assert_eq!(
+            normalized(GitCommand::Commit, &["commit", "--untracked-files", "no"]),
+            args(&["commit", "--untracked-files", "no"])
+        );
+    }
+
+    #[test]
+    fn normalize_args_leaves_unknown_bundles_and_pathspecs_alone() {
+        assert_eq!(
+            normalized(GitCommand::Commit, &["commit", "-aZ"]),
+            args(&["commit", "-aZ"])
+        );
+        assert_eq!(
+            normalized(GitCommand::Commit, &["commit", "-m", "x", "--", "-a"]),
+            args(&["commit", "--message", "x", "--", "-a"])
+        );
+    }
+
+    #[test]
+    fn option_value_reads_both_forms() {
+        let rest = args(&["value", "next"]);
+        let mut rest = rest.iter();
+        assert_eq!(
+            option_value("--file=path", "--file", &mut rest),
+            Some("path")
+        );
+        assert_eq!(option_value("--file", "--file", &mut rest), Some("value"));
+        assert_eq!(option_value("--files", "--file", &mut rest), None);
+        assert_eq!(option_value("--other", "--file", &mut rest), None);
+        assert_eq!(rest.next().map(String::as_str), Some("next"));
+        assert_eq!(option_value("--file", "--file", &mut rest), None);
+    }
+
+    #[test]
+    fn extract_commit_message_reads_every_spelling() {
+        for spelling in [
+            &["commit", "--message=hello"][..],
+            &["commit", "--message", "hello"],
+            &["commit", "-m", "hello"],
+            &["commit", "-mhello"],
+            &["commit", "-am", "hello"],
+        ] {
+            assert_eq!(
+                GitCommandState::extract_commit_message(&args(spelling)).as_deref(),
+                Some("hello"),
+                "{:?}",
+                spelling
+            );
+        }
+        assert_eq!(
+            GitCommandState::extract_commit_message(&args(&["commit", "-m", "a", "-m", "b"]))
+                .as_deref(),
+            Some("a\n\nb")
+        );
+        assert_eq!(
+            GitCommandState::extract_commit_message(&args(&["commit", "--amend"])),
+            None
+        );
+    }
+
+    #[test]
+    fn extract_commit_message_reads_a_message_file_in_both_forms() {
+        let path = std::env::temp_dir().join(format!("githist-message-{}", std::process::id()));
+        std::fs::write(&path, "from a file\n").unwrap();
+        let path = path.display().to_string();
+        for spelling in [
+            args(&["commit", &format!("--file={}", path)]),
+            args(&["commit", "--file", &path]),
+            args(&["commit", "-F", &path]),
+        ] {
+            assert_eq!(
+                GitCommandState::extract_commit_message(&spelling).as_deref(),
+                Some("from a file\n"),
+                "{:?}",
+                spelling
+            );
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn extract_signed_tag_reads_both_forms_of_local_user() {
+        for spelling in [
+            &["tag", "-s", "v1"][..],
+            &["tag", "-u", "KEY", "v1"],
+            &["tag", "--local-user=KEY", "v1"],
+            &["tag", "--local-user", "KEY", "v1"],
+        ] {
+            assert_eq!(
+                GitCommandState::extract_signed_tag(&args(spelling)).as_deref(),
+                Some("v1"),
+                "{:?}",
+                spelling
+            );
+        }
+        assert_eq!(
+            GitCommandState::extract_signed_tag(&args(&["tag", "-m", "msg", "v1"])),
+            None
+        );
+    }
+
+    #[test]
+    fn extract_tag_operation_classifies_creates_deletes_and_lists() {
+        let tag = |raw: &[&str]| {
+            GitCommandState::extract_tag_operation(&normalized(GitCommand::Tag, raw))
+        };
+
+        let created = tag(&["tag", "v1", "HEAD~1"]);
+        assert!(matches!(created.action, TagAction::Create));
+        assert_eq!(created.names, ["v1"]);
+        assert!(!created.annotated);
+        for spelling in [
+            &["tag", "-m", "msg", "v1"][..],
+            &["tag", "--message=msg", "v1"],
+            &["tag", "--message", "msg", "v1"],
+            &["tag", "-a", "v1"],
+        ] {
+            let annotated = tag(spelling);
+            assert!(
+                matches!(annotated.action, TagAction::Create),
+                "{:?}",
+                spelling
+            );
+            assert_eq!(annotated.names, ["v1"], "{:?}", spelling);
+            assert!(annotated.annotated, "{:?}", spelling);
+        }
+
+        let deleted = tag(&["tag", "-d", "v1", "v2"]);
+        assert!(matches!(deleted.action, TagAction::Delete));
+        assert_eq!(deleted.names, ["v1", "v2"]);
+
+        for spelling in [
+            &["tag"][..],
+            &["tag", "-l", "v*"],
+            &["tag", "--sort=-v:refname"],
+            &["tag", "--sort", "-v:refname"],
+            &["tag", "--contains", "abc123"],
+            &["tag", "-n5"],
+        ] {
+            assert!(
+                matches!(tag(spelling).action, TagAction::List),
+                "{:?}",
+                spelling
+            );
+        }
+    }
+
+    #[test]
+    fn extract_force_push_tells_force_from_lease() {
+        let force =
+            |raw: &[&str]| GitCommandState::extract_force_push(&normalized(GitCommand::Push, raw));
+        assert!(matches!(force(&["push", "-f"]), Some(ForcePush::Force)));
+        assert!(matches!(
+            force(&["push", "--force"]),
+            Some(ForcePush::Force)
+        ));
+        assert!(matches!(
+            force(&["push", "origin", "+main"]),
+            Some(ForcePush::Force)
+        ));
+        assert!(matches!(
+            force(&["push", "--force-with-lease"]),
+            Some(ForcePush::ForceWithLease)
+        ));
+        assert!(matches!(
+            force(&["push", "--force-with-lease=main:abc123"]),
+            Some(ForcePush::ForceWithLease)
+        ));
+        assert!(matches!(
+            force(&["push", "--force-with-lease", "--force"]),
+            Some(ForcePush::Force)
+        ));
+        assert!(force(&["push", "--force", "--no-force"]).is_none());
+        assert!(force(&["push", "origin", "main"]).is_none());
+    }
+
+    #[test]
+    fn extract_stash_ref_defaults_to_the_top_stash() {
+        let stash_ref = |raw: &[&str]| GitCommandState::extract_stash_ref(&args(raw));
+        assert_eq!(stash_ref(&["stash", "pop"]).as_deref(), Some("stash@{0}"));
+        assert_eq!(
+            stash_ref(&["stash", "pop", "1"]).as_deref(),
+            Some("stash@{1}")
+        );
+        assert_eq!(
+            stash_ref(&["stash", "apply", "--index", "stash@{2}"]).as_deref(),
+            Some("stash@{2}")
+        );
+        assert_eq!(
+            stash_ref(&["stash", "branch", "topic", "3"]).as_deref(),
+            Some("stash@{3}")
+        );
+        assert_eq!(
+            stash_ref(&["stash", "branch", "topic"]).as_deref(),
+            Some("stash@{0}")
+        );
+        assert_eq!(stash_ref(&["stash", "push"]), None);
+        assert_eq!(stash_ref(&["stash"]), None);
+    }
+
+    #[test]
+    fn extract_jobs_reads_every_spelling() {
+        for (command, raw) in [
+            (GitCommand::Fetch, &["fetch", "--jobs=4"][..]),
+            (GitCommand::Fetch, &["fetch", "--jobs", "4"]),
+            (GitCommand::Fetch, &["fetch", "-j4"]),
+            (GitCommand::Fetch, &["fetch", "-j", "4"]),
+            (GitCommand::Pull, &["pull", "--jobs=4"]),
+            (GitCommand::Pull, &["pull", "--jobs", "4"]),
+            (GitCommand::Pull, &["pull", "-j4"]),
+            (GitCommand::Pull, &["pull", "-j", "4"]),
+            (GitCommand::Fetch, &["fetch", "-j2", "--jobs=4"]),
+        ] {
+            assert_eq!(
+                GitCommandState::extract_jobs(&normalized(command, raw)),
+                Some(4),
+                "{:?}",
+                raw
+            );
+        }
+        assert_eq!(
+            GitCommandState::extract_jobs(&normalized(GitCommand::Fetch, &["fetch", "--", "-j4"])),
+            None
+        );
+    }
+
+    #[test]
+    fn destructive_reason_flags_only_destructive_invocations() {
+        let reason = |raw: &[&str]| destructive_reason(&args(raw));
+        for raw in [
+            &["push", "--force"][..],
+            &["push", "--force-with-lease=main"],
+            &["reset", "--hard", "HEAD~1"],
+            &["clean", "-fd"],
+            &["clean", "--force"],
+            &["branch", "-D", "topic"],
+            &["branch", "--delete", "--force", "topic"],
+        ] {
+            assert!(reason(raw).is_some(), "{:?}", raw);
+        }
+        for raw in [
+            &["push", "origin", "main"][..],
+            &["reset", "--soft", "HEAD~1"],
+            &["clean", "-fn"],
+            &["clean", "--force", "--dry-run"],
+            &["branch", "-d", "topic"],
+            &["status"],
+        ] {
+            assert!(reason(raw).is_none(), "{:?}", raw);
+        }
+    }
+
+    #[test]
+    fn shell_quote_leaves_safe_words_and_quotes_the_rest() {
+        assert_eq!(shell_quote("a/b.c-d_e=f"), "a/b.c-d_e=f");
+        assert_eq!(shell_quote("two words"), "'two words'");
+        assert_eq!(shell_quote("$HOME"), "'$HOME'");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        assert_eq!(shell_quote(""), "''");
+    }
+
+    #[test]
+    fn parse_utc_offset_accepts_hours_minutes_and_seconds() {
+        assert_eq!(
+            parse_utc_offset("+05:30"),
+            time::UtcOffset::from_hms(5, 30, 0).ok()
+        );
+        assert_eq!(
+            parse_utc_offset("-08:00"),
+            time::UtcOffset::from_hms(-8, 0, 0).ok()
+        );
+        assert_eq!(parse_utc_offset("+00:00:00"), Some(time::UtcOffset::UTC));
+        for invalid in ["05:00", "+5", "+xx:00", "+01:00:00:00", ""] {
+            assert_eq!(parse_utc_offset(invalid), None, "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn parse_created_at_reads_what_is_stored() {
+        let parsed = parse_created_at("2024-01-15 9:05:03.5 +01:00:00").unwrap();
+        assert_eq!(
+            (parsed.year(), parsed.month(), parsed.day()),
+            (2024, time::Month::January, 15)
+        );
+        assert_eq!((parsed.hour(), parsed.minute(), parsed.second()), (9, 5, 3));
+        assert_eq!(parsed.nanosecond(), 500_000_000);
+        assert_eq!(parsed.offset().whole_hours(), 1);
+
+        let now = time::OffsetDateTime::now_utc();
+        assert_eq!(parse_created_at(&now.to_string()), Some(now));
+        assert_eq!(parse_created_at("yesterday"), None);
+    }
+
+    #[test]
+    fn extract_submodule_targets_handles_each_subcommand_form() {
+        let targets = |raw: &[&str]| GitCommandState::extract_submodule_targets(&args(raw));
+        let url = "https://example.com/org/lib.git";
+        assert_eq!(
+            targets(&["submodule", "add", url]),
+            (args(&["lib"]), Some(url.to_string()))
+        );
+        assert_eq!(
+            targets(&["submodule", "add", "-b", "main", url, "vendor/lib"]),
+            (args(&["vendor/lib"]), Some(url.to_string()))
+        );
+        assert_eq!(
+            targets(&["submodule", "add", "git@example.com:org/lib.git"]).0,
+            args(&["lib"])
+        );
+        assert_eq!(
+            targets(&["submodule", "update", "--init", "--recursive", "a", "b"]),
+            (args(&["a", "b"]), None)
+        );
+        assert_eq!(
+            targets(&["submodule", "update", "--jobs", "4", "a"]),
+            (args(&["a"]), None)
+        );
+        assert_eq!(
+            targets(&["submodule", "--quiet", "sync", "--", "a"]),
+            (args(&["a"]), None)
+        );
+        assert_eq!(
+            targets(&["submodule", "foreach", "git", "status"]),
+            (vec![], None)
+        );
+        assert_eq!(targets(&["submodule", "status"]), (vec![], None));
+        assert_eq!(targets(&["submodule"]), (vec![], None));
+    }
+
+    #[test]
+    fn concurrent_init_leaves_one_complete_schema() {
+        for attempt in 0..10 {
+            let path = std::env::temp_dir().join(format!(
+                "githist-init-{}-{}",
+                std::process::id(),
+                attempt
+            ));
+            let _ = std::fs::remove_file(&path);
+            let start = std::sync::Arc::new(std::sync::Barrier::new(2));
+            let initializers: Vec<_> = (0..2)
+                .map(|_| {
+                    let (path, start) = (path.clone(), start.clone());
+                    std::thread::spawn(move || {
+                        let mut conn = rusqlite::Connection::open(path).unwrap();
+                        start.wait();
+                        init_history_table(&mut conn).map_err(|err| err.to_string())
+                    })
+                })
+                .collect();
+            for initializer in initializers {
+                assert_eq!(initializer.join().unwrap(), Ok(()));
+            }
+            let conn = rusqlite::Connection::open(&path).unwrap();
+            assert_eq!(history_table_columns(&conn).unwrap(), HISTORY_COLUMNS);
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn scrubbing_a_path_keeps_the_argument_count() {
+        let mut state = GitCommandState {
+            args: args(&["commit", "-m", "secret.txt", "secret.txt"]),
+            normalized_args: args(&["commit", "--message", "secret.txt", "secret.txt"]),
+            files_affected: args(&["secret.txt", "other.txt"]),
+            ..Default::default()
+        };
+        assert!(state.scrub_path("secret.txt"));
+        assert_eq!(
+            state.args,
+            args(&["commit", "-m", SCRUBBED_ARG, SCRUBBED_ARG])
+        );
+        assert_eq!(
+            state.normalized_args,
+            args(&["commit", "--message", SCRUBBED_ARG, SCRUBBED_ARG])
+        );
+        assert_eq!(state.files_affected, args(&["other.txt"]));
+        assert!(!state.scrub_path("secret.txt"));
+    }
+
+    #[test]
+    fn error_exit_codes_are_documented_and_distinct_from_usage_errors() {
+        let errors = [
+            GithistError::NotFound("record".to_string()),
+            GithistError::NoTable,
+            GithistError::GitFailed(std::io::ErrorKind::NotFound.into()),
+            GithistError::DbLocked,
+        ];
+        let mut codes: Vec<u8> = errors.iter().map(GithistError::exit_code).collect();
+        for code in &codes {
+            assert!(![0, 1, 2].contains(code), "{} is already taken", code);
+            assert!(EXIT_CODES_HELP.contains(&format!("\n  {}  ", code)));
+        }
+        codes.sort();
+        codes.dedup();
+        assert_eq!(codes.len(), errors.len());
+    }
+
+    #[test]
+    fn git_commands_are_forwarded_even_where_they_look_like_ours() {
+        let parse = |raw: &[&str]| GitHistoryWrapper::try_parse_from(raw).unwrap().command;
+        for forwarded in [&["git", "log"][..], &["git", "help"], &["git", "log", "-p"]] {
+            assert!(
+                matches!(parse(forwarded), Some(Commands::Other(args)) if args == forwarded[1..]),
+                "{:?}",
+                forwarded
+            );
+        }
+        assert!(matches!(
+            parse(&["git", "mutate-actions"]),
+            Some(Commands::MutateActions)
+        ));
+    }
+
+    #[test]
+    fn wrapper_subcommands_dont_shadow_git_commands() {
+        use clap::CommandFactory;
+        // every command this git knows, not only the ones githist parses
+        let output = std::process::Command::new("git")
+            .arg("--list-cmds=main,others")
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let git_commands = String::from_utf8(output.stdout).unwrap();
+        let git_commands: Vec<&str> = git_commands.lines().collect();
+        assert!(git_commands.contains(&"prune"));
+        for subcommand in GitHistoryWrapper::command().get_subcommands() {
+            let name = subcommand.get_name();
+            assert!(
+                !git_commands.contains(&name)
+                    && GitCommandState::extract_git_command(name).is_err(),
+                "{} is also a git command",
+                name
+            );
         }
     }
 }
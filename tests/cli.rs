@@ -0,0 +1,313 @@
+// End-to-end tests that run the built binary in scratch directories.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("githist-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn githist(dir: &Path) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_githist"));
+    command.current_dir(dir);
+    command
+}
+
+fn git(dir: &Path, args: &[&str]) -> String {
+    let output = Command::new("git")
+        .current_dir(dir)
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "git {:?} failed", args);
+    String::from_utf8(output.stdout).unwrap()
+}
+
+// A fresh repository with an initialized history database.
+fn scratch_repo(name: &str) -> PathBuf {
+    let dir = scratch_dir(name);
+    git(&dir, &["init", "-q"]);
+    assert!(githist(&dir)
+        .arg("command-history-init")
+        .status()
+        .unwrap()
+        .success());
+    dir
+}
+
+// Every recorded command, oldest first.
+fn recorded(dir: &Path) -> Vec<serde_json::Value> {
+    let conn = rusqlite::Connection::open(dir.join(".git_command_history")).unwrap();
+    let mut stmt = conn
+        .prepare("SELECT command FROM git_command_history ORDER BY rowid")
+        .unwrap();
+    let rows = stmt.query_map([], |row| row.get::<_, String>(0)).unwrap();
+    rows.map(|command| serde_json::from_str(&command.unwrap()).unwrap())
+        .collect()
+}
+
+#[cfg(unix)]
+#[test]
+fn timeout_kills_a_hung_git_and_records_it() {
+    use std::os::unix::fs::PermissionsExt;
+    let dir = scratch_dir("timeout");
+    let bin = dir.join("bin");
+    std::fs::create_dir(&bin).unwrap();
+    let fake_git = bin.join("git");
+    // hangs on fetch in a subprocess that outlives it holding the pipes, and
+    // fails straight away for the state githist reads around it
+    std::fs::write(
+        &fake_git,
+        "#!/bin/sh\nif [ \"$1\" = fetch ]; then sleep 30; fi\nexit 1\n",
+    )
+    .unwrap();
+    std::fs::set_permissions(&fake_git, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let path = format!(
+        "{}:{}",
+        bin.display(),
+        std::env::var("PATH").unwrap_or_default()
+    );
+    assert!(githist(&dir)
+        .arg("command-history-init")
+        .status()
+        .unwrap()
+        .success());
+
+    let started = std::time::Instant::now();
+    let output = githist(&dir)
+        .env("PATH", &path)
+        .args(["--timeout", "1", "fetch"])
+        .output()
+        .unwrap();
+
+    assert!(started.elapsed() < std::time::Duration::from_secs(10));
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("timed out after 1s"));
+    let rows = recorded(&dir);
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0]["timed_out"], true);
+}
+
+#[test]
+fn dash_c_resolves_files_and_export_script_against_that_directory() {
+    let dir = scratch_repo("dash-c");
+    std::fs::create_dir(dir.join("subdir")).unwrap();
+    std::fs::write(dir.join("subdir").join("file"), "contents\n").unwrap();
+
+    let status = githist(&dir)
+        .args(["-C", "subdir", "add", "file"])
+        .status()
+        .unwrap();
+
+    assert!(status.success());
+    assert_eq!(
+        git(&dir, &["diff", "--cached", "--name-only"]),
+        "subdir/file\n"
+    );
+    let rows = recorded(&dir);
+    assert_eq!(rows.len(), 1);
+    assert_eq!(
+        rows[0]["files_affected"],
+        serde_json::json!(["subdir/file"])
+    );
+    let cwd = rows[0]["cwd"].as_str().unwrap();
+    assert!(cwd.ends_with("subdir"), "cwd was {}", cwd);
+
+    let script = githist(&dir).arg("export-script").output().unwrap();
+    let script = String::from_utf8(script.stdout).unwrap();
+    assert!(
+        script.contains(&format!("git -C {} add file\n", cwd)),
+        "script was:\n{}",
+        script
+    );
+}
+
+#[test]
+fn affected_files_are_recorded_relative_to_the_repository_from_subdirectories() {
+    let dir = scratch_repo("subdirectories");
+    let nested = dir.join("a").join("b");
+    std::fs::create_dir_all(&nested).unwrap();
+    std::fs::write(dir.join("top.txt"), "top\n").unwrap();
+    std::fs::write(dir.join("a").join("middle.txt"), "middle\n").unwrap();
+    std::fs::write(nested.join("deep.txt"), "deep\n").unwrap();
+    let outside = scratch_dir("subdirectories-outside");
+    std::fs::write(outside.join("elsewhere.txt"), "elsewhere\n").unwrap();
+    let outside_file = outside.join("elsewhere.txt").display().to_string();
+    // githist keeps its database in the directory it runs from
+    assert!(githist(&nested)
+        .arg("command-history-init")
+        .status()
+        .unwrap()
+        .success());
+
+    let status = githist(&nested)
+        .args(["add", "deep.txt", "../middle.txt", "../../top.txt"])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    // differing files make this exit 1, but it is recorded all the same
+    githist(&nested)
+        .args(["diff", "--no-index", "deep.txt", &outside_file])
+        .status()
+        .unwrap();
+
+    let rows = recorded(&nested);
+    assert_eq!(rows.len(), 2);
+    assert_eq!(
+        rows[0]["files_affected"],
+        serde_json::json!(["a/b/deep.txt", "a/middle.txt", "top.txt"])
+    );
+    assert_eq!(rows[0]["files_outside_repo"], false);
+    assert_eq!(
+        rows[1]["files_affected"],
+        serde_json::json!(["a/b/deep.txt", outside_file])
+    );
+    assert_eq!(rows[1]["files_outside_repo"], true);
+}
+
+#[test]
+fn read_subcommands_work_on_a_copied_database_without_a_repository() {
+    let repo = scratch_repo("copied-db-repo");
+    std::fs::write(repo.join("file"), "contents\n").unwrap();
+    assert!(githist(&repo)
+        .args(["add", "file"])
+        .status()
+        .unwrap()
+        .success());
+    let elsewhere = scratch_dir("copied-db-elsewhere");
+    std::fs::copy(
+        repo.join(".git_command_history"),
+        elsewhere.join(".git_command_history"),
+    )
+    .unwrap();
+    let no_git = elsewhere.join("empty-path");
+    std::fs::create_dir(&no_git).unwrap();
+
+    for path in [std::env::var_os("PATH").unwrap_or_default(), no_git.into()] {
+        let mutations = githist(&elsewhere)
+            .env("PATH", &path)
+            .arg("mutate-actions")
+            .output()
+            .unwrap();
+        assert!(mutations.status.success(), "PATH={:?}", path);
+        assert!(String::from_utf8_lossy(&mutations.stdout).contains(r#""command":"add""#));
+        let sessions = githist(&elsewhere)
+            .env("PATH", &path)
+            .arg("sessions")
+            .output()
+            .unwrap();
+        assert!(sessions.status.success(), "PATH={:?}", path);
+        assert!(String::from_utf8_lossy(&sessions.stdout).contains("1 commands"));
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn non_utf8_filenames_are_recorded_lossily_and_flagged() {
+    use std::os::unix::ffi::OsStrExt;
+    let dir = scratch_repo("non-utf8");
+    let commit = |message: &str| {
+        githist(&dir)
+            .env("GIT_AUTHOR_NAME", "Tester")
+            .env("GIT_AUTHOR_EMAIL", "tester@example.com")
+            .env("GIT_COMMITTER_NAME", "Tester")
+            .env("GIT_COMMITTER_EMAIL", "tester@example.com")
+            .args(["commit", "--allow-empty", "-m", message])
+            .status()
+            .unwrap()
+    };
+    assert!(commit("initial").success());
+    // "café.txt" in Latin-1
+    let name = std::ffi::OsStr::from_bytes(b"caf\xe9.txt");
+    std::fs::write(dir.join(name), "contents\n").unwrap();
+    assert!(Command::new("git")
+        .current_dir(&dir)
+        .args(["add", "--"])
+        .arg(name)
+        .status()
+        .unwrap()
+        .success());
+
+    assert!(commit("add a Latin-1 name").success());
+
+    let rows = recorded(&dir);
+    assert_eq!(rows.len(), 2);
+    assert_eq!(rows[0]["lossy_utf8"], false);
+    assert_eq!(rows[1]["lossy_utf8"], true);
+    assert_eq!(
+        rows[1]["file_changes"],
+        serde_json::json!([{ "status": "added", "path": "caf\u{fffd}.txt", "old_path": null }])
+    );
+}
+
+#[test]
+fn dry_run_is_refused_by_subcommands_that_would_ignore_it() {
+    let dir = scratch_repo("dry-run");
+    std::fs::write(dir.join("secret.txt"), "").unwrap();
+    assert!(githist(&dir)
+        .args(["add", "secret.txt"])
+        .status()
+        .unwrap()
+        .success());
+
+    for refused in [
+        &["nuke-file", "secret.txt", "--dry-run"][..],
+        &["--dry-run", "compact"],
+        &["snapshot", "--dry-run"],
+    ] {
+        let output = githist(&dir).args(refused).output().unwrap();
+        assert!(!output.status.success(), "{:?}", refused);
+    }
+    assert_eq!(recorded(&dir).len(), 1);
+    assert_eq!(recorded(&dir)[0]["files_affected"][0], "secret.txt");
+
+    let output = githist(&dir)
+        .args(["prune-history", "--keep-last", "0", "--dry-run"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "would delete 1 rows\n"
+    );
+    assert_eq!(recorded(&dir).len(), 1);
+}
+
+#[test]
+fn a_declined_destructive_command_fails_and_one_yes_answers_every_prompt() {
+    let dir = scratch_repo("confirm");
+    git(&dir, &["commit", "-q", "--allow-empty", "-m", "first"]);
+    let output = githist(&dir)
+        .args(["--confirm-destructive", "reset", "--hard", "HEAD"])
+        .stdin(std::process::Stdio::null())
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(recorded(&dir).is_empty());
+
+    git(&dir, &["branch", "old"]);
+    assert!(githist(&dir)
+        .args(["--confirm-destructive", "--yes", "branch", "-D", "old"])
+        .stdin(std::process::Stdio::null())
+        .status()
+        .unwrap()
+        .success());
+    assert_eq!(recorded(&dir).len(), 1);
+    let conn = rusqlite::Connection::open(dir.join(".git_command_history")).unwrap();
+    let id: String = conn
+        .query_row("SELECT id FROM git_command_history", [], |row| row.get(0))
+        .unwrap();
+    // before and after the subcommand
+    for yes in [&["--yes", "reset-to", &id][..], &["forget", &id, "--yes"]] {
+        let output = githist(&dir)
+            .args(yes)
+            .stdin(std::process::Stdio::null())
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}", yes);
+        assert!(!String::from_utf8_lossy(&output.stdout).contains("[y/N]"));
+    }
+}